@@ -57,22 +57,14 @@ async fn async_readline(prompt: &str, output_rx: channel::Receiver<String>) -> O
             let input_future = async {
                 loop {
                     match state.edit_feed() {
-                        Ok(Some(line)) => return Some(line),
-                        Ok(None) => return None, // EOF
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        Ok(FeedResult::Line(line)) => return Some(line),
+                        Ok(FeedResult::Pending) => {
                             // No data available, yield and retry
                             Timer::after(Duration::from_millis(10)).await;
                             continue;
                         }
-                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
-                            // Ctrl-C
-                            return None;
-                        }
-                        Err(_) => {
-                            // Other error, keep trying
-                            Timer::after(Duration::from_millis(10)).await;
-                            continue;
-                        }
+                        // Ctrl-C, Ctrl-D or an error
+                        Ok(FeedResult::Eof | FeedResult::Interrupted) | Err(_) => return None,
                     }
                 }
             };
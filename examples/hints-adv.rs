@@ -155,20 +155,6 @@ macro_rules! cmds {
     }};
 }
 
-thread_local! {
-    static COMMANDS: std::cell::RefCell<Option<Arc<CmdNode>>> = std::cell::RefCell::new(None);
-}
-
-fn set_commands(commands: CmdNode) {
-    COMMANDS.with(|c| {
-        *c.borrow_mut() = Some(Arc::new(commands));
-    });
-}
-
-fn hints_tls(buf: &str) -> Option<(String, i32, bool)> {
-    COMMANDS.with(|c| c.borrow().as_ref().and_then(|commands| commands.hints(buf)))
-}
-
 fn main() {
     let commands = cmds! {
         "git" => {
@@ -198,8 +184,9 @@ fn main() {
     assert!(commands.hints("git ch").is_some()); // Should complete "eckout"
     assert!(commands.hints("git checkout").is_some()); // Should suggest "-b" or "<branch>"
 
-    set_commands(commands.clone());
-    linenoise_set_hints_callback(hints_tls);
+    // The callback can capture the command tree directly.
+    let commands = Arc::new(commands);
+    linenoise_set_hints_callback(move |buf| commands.hints(buf));
 
     while let Some(input) = linenoise("> ") {
         match input.as_str() {
@@ -104,7 +104,9 @@ fn main() {
                     }
 
                     match parts[0] {
-                        "/historylen" => {
+                        "/historylen" =>
+                        {
+                            #[allow(clippy::collapsible_if)]
                             if parts.len() > 1 {
                                 if let Ok(len) = parts[1].parse::<usize>() {
                                     linenoise_history_set_max_len(len);
@@ -167,24 +169,19 @@ fn async_readline(prompt: &str) -> Option<String> {
             } else if retval > 0 {
                 // Input available
                 match state.edit_feed() {
-                    Ok(Some(line)) => {
+                    Ok(FeedResult::Line(line)) => {
                         let _ = state.edit_stop();
                         return Some(line);
                     }
-                    Ok(None) => {
-                        // EOF
-                        let _ = state.edit_stop();
-                        return None;
+                    Ok(FeedResult::Pending) => {
+                        // Need more input
+                        continue;
                     }
-                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
-                        // Ctrl-C
+                    Ok(FeedResult::Eof | FeedResult::Interrupted) | Err(_) => {
+                        // Ctrl-D, Ctrl-C, or an error
                         let _ = state.edit_stop();
                         return None;
                     }
-                    Err(_) => {
-                        // Need more input
-                        continue;
-                    }
                 }
             } else {
                 // Timeout occurred - simulate async output
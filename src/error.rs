@@ -0,0 +1,44 @@
+//! Why reading a line failed.
+
+use std::{fmt, io};
+
+/// The reason no line was read: the user gave up on it, the input ended,
+/// or an I/O error.
+#[derive(Debug)]
+pub enum Error {
+    /// Ctrl-C was pressed.
+    Interrupted,
+    /// Ctrl-D was pressed on an empty line.
+    Eof,
+    /// The input ended, at the end of a file or pipe or with the terminal
+    /// hanging up.
+    Closed,
+    /// Reading from or writing to the terminal failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Interrupted => write!(f, "interrupted"),
+            Error::Eof => write!(f, "end of input"),
+            Error::Closed => write!(f, "input closed"),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,671 @@
+//! Key events, and the keymap binding them to editor commands.
+
+use std::collections::HashMap;
+use std::mem;
+use std::ops::BitOr;
+
+use super::{KeyCallback, Shared};
+
+/// A key, without its modifiers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Esc,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// Function key F1, F2...
+    F(u8),
+}
+
+/// Modifier keys held with a key, combined with `|`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1);
+    pub const ALT: Modifiers = Modifiers(2);
+    pub const CTRL: Modifiers = Modifiers(4);
+
+    /// Whether all the modifiers in `other` are held.
+    pub const fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// These modifiers without the ones in `other`.
+    pub const fn without(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+/// A key press read from the terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    pub const fn new(code: KeyCode, modifiers: Modifiers) -> Self {
+        KeyEvent { code, modifiers }
+    }
+
+    /// Ctrl+`c`, like `KeyEvent::ctrl('a')` for Ctrl-A.
+    pub const fn ctrl(c: char) -> Self {
+        Self::new(KeyCode::Char(c.to_ascii_lowercase()), Modifiers::CTRL)
+    }
+
+    /// Alt+`c`, which terminals send as Esc followed by `c`.
+    pub const fn alt(c: char) -> Self {
+        Self::new(KeyCode::Char(c), Modifiers::ALT)
+    }
+}
+
+impl From<KeyCode> for KeyEvent {
+    fn from(code: KeyCode) -> Self {
+        Self::new(code, Modifiers::NONE)
+    }
+}
+
+impl From<char> for KeyEvent {
+    fn from(c: char) -> Self {
+        Self::new(KeyCode::Char(c), Modifiers::NONE)
+    }
+}
+
+const ESC: u8 = 0x1b;
+
+/// The sequences a terminal in bracketed paste mode puts around pasted
+/// text.
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Input read from the terminal.
+pub(super) enum Input {
+    Key(KeyEvent),
+    /// Text pasted with bracketed paste mode on, its line breaks as `\n`.
+    Paste(String),
+}
+
+/// Outcome of decoding the input bytes received so far.
+enum Decoded {
+    Key(KeyEvent),
+    /// More bytes are needed.
+    Pending,
+    /// Not a sequence we understand.
+    Invalid,
+}
+
+/// Turns input bytes into key events and pastes. The bytes of a partially
+/// arrived escape sequence or UTF-8 character are kept until the rest comes
+/// in.
+pub(super) struct KeyDecoder {
+    buf: Vec<u8>,
+    /// Inside a bracketed paste, whose text is collected in `buf`.
+    paste: bool,
+}
+
+impl KeyDecoder {
+    pub(super) fn new() -> Self {
+        KeyDecoder {
+            buf: Vec::new(),
+            paste: false,
+        }
+    }
+
+    /// Feed a byte, returning the key or paste it completes, if any.
+    /// Sequences that are not understood are dropped whole.
+    pub(super) fn push(&mut self, byte: u8) -> Option<Input> {
+        self.buf.push(byte);
+        if self.paste {
+            if !self.buf.ends_with(PASTE_END) {
+                return None;
+            }
+            self.buf.truncate(self.buf.len() - PASTE_END.len());
+            self.paste = false;
+            let text = String::from_utf8_lossy(&mem::take(&mut self.buf))
+                .replace("\r\n", "\n")
+                .replace('\r', "\n");
+            return Some(Input::Paste(text));
+        }
+        if self.buf == PASTE_START {
+            self.buf.clear();
+            self.paste = true;
+            return None;
+        }
+
+        match decode(&self.buf, false) {
+            Decoded::Pending => None,
+            Decoded::Key(key) => {
+                self.buf.clear();
+                Some(Input::Key(key))
+            }
+            Decoded::Invalid => {
+                self.buf.clear();
+                None
+            }
+        }
+    }
+
+    /// Whether part of a key or of a paste has been received.
+    pub(super) fn is_pending(&self) -> bool {
+        self.paste || !self.buf.is_empty()
+    }
+
+    /// Whether the bytes received so far are a key by themselves if
+    /// nothing follows, like an Esc that may also start an escape sequence.
+    pub(super) fn is_ambiguous(&self) -> bool {
+        !self.paste && !self.buf.is_empty() && matches!(decode(&self.buf, true), Decoded::Key(_))
+    }
+
+    /// Take the bytes received so far as a whole key, nothing having
+    /// followed them in time.
+    pub(super) fn flush(&mut self) -> Option<Input> {
+        if self.paste {
+            return None;
+        }
+        let decoded = decode(&self.buf, true);
+        self.buf.clear();
+        match decoded {
+            Decoded::Key(key) => Some(Input::Key(key)),
+            _ => None,
+        }
+    }
+}
+
+/// Decode `buf` as a single key. With `at_end`, no more bytes follow, so
+/// Esc, `ESC [` and `ESC O` are keys rather than sequence starts.
+fn decode(buf: &[u8], at_end: bool) -> Decoded {
+    let Some((&first, rest)) = buf.split_first() else {
+        return Decoded::Pending;
+    };
+    if first != ESC {
+        return decode_plain(buf);
+    }
+
+    match rest {
+        [] if at_end => Decoded::Key(KeyCode::Esc.into()),
+        [] => Decoded::Pending,
+        [b'[' | b'O'] if at_end => Decoded::Key(KeyEvent::alt(rest[0] as char)),
+        [b'['] | [b'O'] => Decoded::Pending,
+        [b'[', seq @ ..] => decode_csi(seq),
+        [b'O', seq @ ..] => decode_ss3(seq),
+        // Esc followed by a key is that key with Alt
+        _ => match decode(rest, at_end) {
+            Decoded::Key(key) => {
+                Decoded::Key(KeyEvent::new(key.code, key.modifiers | Modifiers::ALT))
+            }
+            decoded => decoded,
+        },
+    }
+}
+
+/// Decode a key that is not an escape sequence: a control character, or
+/// a possibly multi-byte UTF-8 character.
+fn decode_plain(buf: &[u8]) -> Decoded {
+    let first = buf[0];
+    let code = match first {
+        b'\t' => KeyCode::Tab,
+        b'\r' => KeyCode::Enter,
+        0x7f => KeyCode::Backspace,
+        0 => return Decoded::Key(KeyEvent::ctrl(' ')),
+        1..=26 => return Decoded::Key(KeyEvent::ctrl((b'a' + first - 1) as char)),
+        28..=31 => return Decoded::Key(KeyEvent::ctrl((b'\\' + first - 28) as char)),
+        0x20..0x7f => KeyCode::Char(first as char),
+        _ => {
+            let len = match first {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return Decoded::Invalid,
+            };
+            if buf[1..].iter().any(|&b| b & 0xc0 != 0x80) {
+                return Decoded::Invalid;
+            }
+            if buf.len() < len {
+                return Decoded::Pending;
+            }
+            match std::str::from_utf8(buf).ok().and_then(|s| s.chars().next()) {
+                Some(c) => KeyCode::Char(c),
+                None => return Decoded::Invalid,
+            }
+        }
+    };
+    Decoded::Key(code.into())
+}
+
+/// Modifiers encoded in a CSI parameter: 1 plus a bitmask of Shift (1),
+/// Alt (2), Ctrl (4) and Meta (8), taken as Alt.
+fn modifiers_param(param: Option<u32>) -> Modifiers {
+    let bits = param.unwrap_or(1).saturating_sub(1);
+    let mut modifiers = Modifiers::NONE;
+    if bits & 1 != 0 {
+        modifiers = modifiers | Modifiers::SHIFT;
+    }
+    if bits & (2 | 8) != 0 {
+        modifiers = modifiers | Modifiers::ALT;
+    }
+    if bits & 4 != 0 {
+        modifiers = modifiers | Modifiers::CTRL;
+    }
+    modifiers
+}
+
+/// Split `;` separated numeric parameters, missing ones being `None`.
+fn parse_params(params: &[u8]) -> Option<Vec<Option<u32>>> {
+    params
+        .split(|&b| b == b';')
+        .map(|p| match p {
+            [] => Some(None),
+            _ => std::str::from_utf8(p).ok()?.parse().ok().map(Some),
+        })
+        .collect()
+}
+
+/// Arrows, Home and End, by the final byte of their sequence.
+fn cursor_key(byte: u8) -> Option<KeyCode> {
+    Some(match byte {
+        b'A' | b'a' => KeyCode::Up,
+        b'B' | b'b' => KeyCode::Down,
+        b'C' | b'c' => KeyCode::Right,
+        b'D' | b'd' => KeyCode::Left,
+        b'H' => KeyCode::Home,
+        b'F' => KeyCode::End,
+        _ => return None,
+    })
+}
+
+/// Decode the rest of an `ESC [` sequence: parameter bytes followed by a
+/// final byte.
+fn decode_csi(seq: &[u8]) -> Decoded {
+    let (&last, params) = seq.split_last().unwrap();
+    // rxvt ends modified keys with `$` (Shift) and `^` (Ctrl)
+    if !(0x40..=0x7e).contains(&last) && last != b'$' {
+        return if seq.iter().all(|b| (0x20..=0x3f).contains(b)) {
+            Decoded::Pending
+        } else {
+            Decoded::Invalid
+        };
+    }
+    let Some(nums) = parse_params(params) else {
+        return Decoded::Invalid;
+    };
+    let param = |i: usize| nums.get(i).copied().flatten();
+
+    let (code, modifiers) = match last {
+        // rxvt Shift+arrows
+        b'a'..=b'd' if params.is_empty() => (cursor_key(last).unwrap(), Modifiers::SHIFT),
+        b'A'..=b'D' | b'H' | b'F' => (cursor_key(last).unwrap(), modifiers_param(param(1))),
+        b'P'..=b'S' => (KeyCode::F(last - b'P' + 1), modifiers_param(param(1))),
+        b'Z' => (KeyCode::Tab, Modifiers::SHIFT | modifiers_param(param(1))),
+        b'~' | b'$' | b'^' => {
+            let code = match param(0) {
+                Some(1 | 7) => KeyCode::Home,
+                Some(2) => KeyCode::Insert,
+                Some(3) => KeyCode::Delete,
+                Some(4 | 8) => KeyCode::End,
+                Some(5) => KeyCode::PageUp,
+                Some(6) => KeyCode::PageDown,
+                Some(n @ 11..=15) => KeyCode::F(n as u8 - 10),
+                Some(n @ 17..=21) => KeyCode::F(n as u8 - 11),
+                Some(n @ 23..=24) => KeyCode::F(n as u8 - 12),
+                _ => return Decoded::Invalid,
+            };
+            let modifiers = match last {
+                b'$' => Modifiers::SHIFT,
+                b'^' => Modifiers::CTRL,
+                _ => modifiers_param(param(1)),
+            };
+            (code, modifiers)
+        }
+        // "CSI u" encoding of keys by code point
+        b'u' => {
+            let modifiers = modifiers_param(param(1));
+            let code = match param(0).and_then(char::from_u32) {
+                Some('\t') => KeyCode::Tab,
+                Some('\r') => KeyCode::Enter,
+                Some('\x1b') => KeyCode::Esc,
+                Some('\x7f') => KeyCode::Backspace,
+                Some(c) if modifiers.contains(Modifiers::CTRL) => {
+                    KeyCode::Char(c.to_ascii_lowercase())
+                }
+                Some(c) => KeyCode::Char(c),
+                None => return Decoded::Invalid,
+            };
+            (code, modifiers)
+        }
+        _ => return Decoded::Invalid,
+    };
+    Decoded::Key(KeyEvent::new(code, modifiers))
+}
+
+/// Decode the rest of an `ESC O` sequence, possibly with a modifiers
+/// parameter before the final byte.
+fn decode_ss3(seq: &[u8]) -> Decoded {
+    let (&last, params) = seq.split_last().unwrap();
+    if last.is_ascii_digit() || last == b';' {
+        return Decoded::Pending;
+    }
+    let Some(nums) = parse_params(params) else {
+        return Decoded::Invalid;
+    };
+    let modifiers = modifiers_param(nums.last().copied().flatten());
+
+    let (code, modifiers) = match last {
+        // rxvt Ctrl+arrows
+        b'a'..=b'd' => (cursor_key(last).unwrap(), Modifiers::CTRL),
+        b'A'..=b'D' | b'H' | b'F' => (cursor_key(last).unwrap(), modifiers),
+        b'P'..=b'S' => (KeyCode::F(last - b'P' + 1), modifiers),
+        b'M' => (KeyCode::Enter, modifiers),
+        _ => return Decoded::Invalid,
+    };
+    Decoded::Key(KeyEvent::new(code, modifiers))
+}
+
+/// Editor commands that keys can be bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Cmd {
+    /// Return the line, unless a validator finds it incomplete or invalid.
+    AcceptLine,
+    /// Insert a line break, continuing the input on a new line.
+    InsertNewline,
+    /// Stop editing, returning an `Interrupted` error.
+    Interrupt,
+    /// Delete the character at the cursor, or end input if the line is
+    /// empty.
+    DeleteCharOrEof,
+    /// Complete the text at the cursor with the completer callback.
+    Complete,
+    MoveLeft,
+    /// Move right, or accept the autosuggestion at the end of the line.
+    MoveRight,
+    MoveWordLeft,
+    /// Move one word right, or accept a word of the autosuggestion.
+    MoveWordRight,
+    MoveHome,
+    /// Move to the end of the line, or accept the autosuggestion.
+    MoveEnd,
+    DeleteChar,
+    DeleteCharLeft,
+    /// Kill from the cursor to the end of the line.
+    KillLine,
+    KillWholeLine,
+    KillWordLeft,
+    KillWordRight,
+    /// Insert the most recently killed text.
+    Yank,
+    /// Replace the text just yanked with the previous killed text.
+    YankPop,
+    TransposeChars,
+    /// Swap the words before and after the cursor.
+    TransposeWords,
+    /// Upper case the word after the cursor.
+    UpcaseWord,
+    /// Lower case the word after the cursor.
+    DowncaseWord,
+    /// Capitalize the word after the cursor.
+    CapitalizeWord,
+    HistoryPrevious,
+    HistoryNext,
+    /// Start a reverse incremental history search, or find the next
+    /// older match during one.
+    HistorySearchBackward,
+    /// Start a forward incremental history search, or find the next
+    /// newer match during one.
+    HistorySearchForward,
+    Undo,
+    Redo,
+    ClearScreen,
+}
+
+/// What a key sequence is bound to.
+#[derive(Clone)]
+pub(super) enum Binding {
+    Cmd(Cmd),
+    Func(Shared<KeyCallback>),
+}
+
+/// Key sequences and what they are bound to.
+#[derive(Clone)]
+pub(super) struct Keymap {
+    bindings: HashMap<Vec<KeyEvent>, Binding>,
+}
+
+impl Keymap {
+    /// The default, Emacs style, bindings.
+    pub(super) fn new() -> Self {
+        use KeyCode::*;
+
+        let defaults: &[(&[KeyEvent], Cmd)] = &[
+            (&[KeyEvent::new(Enter, Modifiers::NONE)], Cmd::AcceptLine),
+            (&[KeyEvent::new(Enter, Modifiers::ALT)], Cmd::InsertNewline),
+            (&[KeyEvent::ctrl('c')], Cmd::Interrupt),
+            (&[KeyEvent::ctrl('d')], Cmd::DeleteCharOrEof),
+            (&[KeyEvent::new(Tab, Modifiers::NONE)], Cmd::Complete),
+            (
+                &[KeyEvent::new(Backspace, Modifiers::NONE)],
+                Cmd::DeleteCharLeft,
+            ),
+            (&[KeyEvent::ctrl('h')], Cmd::DeleteCharLeft),
+            (&[KeyEvent::new(Delete, Modifiers::NONE)], Cmd::DeleteChar),
+            (&[KeyEvent::ctrl('u')], Cmd::KillWholeLine),
+            (&[KeyEvent::ctrl('k')], Cmd::KillLine),
+            (&[KeyEvent::ctrl('w')], Cmd::KillWordLeft),
+            (
+                &[KeyEvent::new(Backspace, Modifiers::ALT)],
+                Cmd::KillWordLeft,
+            ),
+            (&[KeyEvent::alt('d')], Cmd::KillWordRight),
+            (&[KeyEvent::ctrl('y')], Cmd::Yank),
+            (&[KeyEvent::alt('y')], Cmd::YankPop),
+            (&[KeyEvent::ctrl('a')], Cmd::MoveHome),
+            (&[KeyEvent::new(Home, Modifiers::NONE)], Cmd::MoveHome),
+            (&[KeyEvent::ctrl('e')], Cmd::MoveEnd),
+            (&[KeyEvent::new(End, Modifiers::NONE)], Cmd::MoveEnd),
+            (&[KeyEvent::ctrl('b')], Cmd::MoveLeft),
+            (&[KeyEvent::new(Left, Modifiers::NONE)], Cmd::MoveLeft),
+            (&[KeyEvent::ctrl('f')], Cmd::MoveRight),
+            (&[KeyEvent::new(Right, Modifiers::NONE)], Cmd::MoveRight),
+            (&[KeyEvent::alt('b')], Cmd::MoveWordLeft),
+            (&[KeyEvent::alt('f')], Cmd::MoveWordRight),
+            (&[KeyEvent::new(Left, Modifiers::CTRL)], Cmd::MoveWordLeft),
+            (&[KeyEvent::new(Right, Modifiers::CTRL)], Cmd::MoveWordRight),
+            (&[KeyEvent::ctrl('p')], Cmd::HistoryPrevious),
+            (&[KeyEvent::new(Up, Modifiers::NONE)], Cmd::HistoryPrevious),
+            (&[KeyEvent::ctrl('n')], Cmd::HistoryNext),
+            (&[KeyEvent::new(Down, Modifiers::NONE)], Cmd::HistoryNext),
+            (&[KeyEvent::ctrl('r')], Cmd::HistorySearchBackward),
+            (&[KeyEvent::ctrl('s')], Cmd::HistorySearchForward),
+            (&[KeyEvent::ctrl('_')], Cmd::Undo),
+            (&[KeyEvent::ctrl('x'), KeyEvent::ctrl('u')], Cmd::Undo),
+            (&[KeyEvent::ctrl('x'), KeyEvent::ctrl('r')], Cmd::Redo),
+            (&[KeyEvent::ctrl('l')], Cmd::ClearScreen),
+            (&[KeyEvent::ctrl('t')], Cmd::TransposeChars),
+            (&[KeyEvent::alt('t')], Cmd::TransposeWords),
+            (&[KeyEvent::alt('u')], Cmd::UpcaseWord),
+            (&[KeyEvent::alt('l')], Cmd::DowncaseWord),
+            (&[KeyEvent::alt('c')], Cmd::CapitalizeWord),
+        ];
+
+        Keymap {
+            bindings: defaults
+                .iter()
+                .map(|&(keys, cmd)| (keys.to_vec(), Binding::Cmd(cmd)))
+                .collect(),
+        }
+    }
+
+    pub(super) fn bind(&mut self, keys: &[KeyEvent], binding: Binding) {
+        if !keys.is_empty() {
+            self.bindings.insert(keys.to_vec(), binding);
+        }
+    }
+
+    pub(super) fn unbind(&mut self, keys: &[KeyEvent]) {
+        self.bindings.remove(keys);
+    }
+
+    pub(super) fn get(&self, keys: &[KeyEvent]) -> Option<&Binding> {
+        self.bindings.get(keys)
+    }
+
+    /// The command `keys` are bound to, if they are bound to one.
+    pub(super) fn command(&self, keys: &[KeyEvent]) -> Option<Cmd> {
+        match self.bindings.get(keys)? {
+            Binding::Cmd(cmd) => Some(*cmd),
+            Binding::Func(_) => None,
+        }
+    }
+
+    /// Whether `keys` start a longer bound sequence.
+    pub(super) fn is_prefix(&self, keys: &[KeyEvent]) -> bool {
+        self.bindings
+            .keys()
+            .any(|seq| seq.len() > keys.len() && seq.starts_with(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyCode::*;
+
+    const SHIFT: Modifiers = Modifiers::SHIFT;
+    const ALT: Modifiers = Modifiers::ALT;
+    const CTRL: Modifiers = Modifiers::CTRL;
+
+    /// The keys `bytes` decode to, taking what is left at the end as a
+    /// whole key.
+    fn keys(decoder: &mut KeyDecoder, bytes: &[u8]) -> Vec<KeyEvent> {
+        let mut keys = Vec::new();
+        for &byte in bytes {
+            if let Some(Input::Key(key)) = decoder.push(byte) {
+                keys.push(key);
+            }
+        }
+        if let Some(Input::Key(key)) = decoder.flush() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn decodes_keys() {
+        let cases: &[(&[u8], KeyEvent)] = &[
+            (b"a", 'a'.into()),
+            ("é".as_bytes(), 'é'.into()),
+            (b"\x01", KeyEvent::ctrl('a')),
+            (b"\x1f", KeyEvent::ctrl('_')),
+            (b"\r", Enter.into()),
+            (b"\x7f", Backspace.into()),
+            (b"\x1b", Esc.into()),
+            (b"\x1bb", KeyEvent::alt('b')),
+            (b"\x1b[", KeyEvent::alt('[')),
+            (b"\x1b[A", Up.into()),
+            (b"\x1b[1;5D", KeyEvent::new(Left, CTRL)),
+            (b"\x1b[1;3C", KeyEvent::new(Right, ALT)),
+            (b"\x1b[1;9C", KeyEvent::new(Right, ALT)),
+            (b"\x1b[Z", KeyEvent::new(Tab, SHIFT)),
+            (b"\x1b[3~", Delete.into()),
+            (b"\x1b[3;2~", KeyEvent::new(Delete, SHIFT)),
+            (b"\x1b[15~", F(5).into()),
+            (b"\x1b[24~", F(12).into()),
+            (b"\x1b[1;2P", KeyEvent::new(F(1), SHIFT)),
+            // rxvt
+            (b"\x1b[7~", Home.into()),
+            (b"\x1b[8~", End.into()),
+            (b"\x1b[3$", KeyEvent::new(Delete, SHIFT)),
+            (b"\x1b[5^", KeyEvent::new(PageUp, CTRL)),
+            (b"\x1b[a", KeyEvent::new(Up, SHIFT)),
+            (b"\x1bOd", KeyEvent::new(Left, CTRL)),
+            // CSI u
+            (b"\x1b[97;5u", KeyEvent::ctrl('a')),
+            (b"\x1b[65;5u", KeyEvent::ctrl('a')),
+            (b"\x1b[13;2u", KeyEvent::new(Enter, SHIFT)),
+            (b"\x1b[27u", Esc.into()),
+            // SS3
+            (b"\x1bOP", F(1).into()),
+            (b"\x1bOS", F(4).into()),
+            (b"\x1bO5Q", KeyEvent::new(F(2), CTRL)),
+            (b"\x1bOH", Home.into()),
+            (b"\x1bOM", Enter.into()),
+        ];
+        for &(bytes, key) in cases {
+            assert_eq!(keys(&mut KeyDecoder::new(), bytes), [key], "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn drops_unknown_sequences() {
+        let mut decoder = KeyDecoder::new();
+        assert_eq!(keys(&mut decoder, b"\x1b[99~x"), ['x'.into()]);
+        assert_eq!(keys(&mut decoder, b"\x1bOzx"), ['x'.into()]);
+        assert_eq!(keys(&mut decoder, b"\xffx"), ['x'.into()]);
+    }
+
+    #[test]
+    fn sequences_split_across_pushes() {
+        let mut decoder = KeyDecoder::new();
+        for &byte in b"\x1b[1;5" {
+            assert!(decoder.push(byte).is_none());
+            assert!(decoder.is_pending());
+        }
+        assert!(
+            matches!(decoder.push(b'D'), Some(Input::Key(key)) if key == KeyEvent::new(Left, CTRL))
+        );
+        assert!(!decoder.is_pending());
+
+        assert!(decoder.push(0xc3).is_none());
+        assert!(decoder.is_pending());
+        assert!(matches!(decoder.push(0xa9), Some(Input::Key(key)) if key == 'é'.into()));
+
+        for &byte in b"\x1b[200~a\r\nb\x1b[201" {
+            assert!(decoder.push(byte).is_none());
+        }
+        assert!(matches!(decoder.push(b'~'), Some(Input::Paste(text)) if text == "a\nb"));
+    }
+
+    #[test]
+    fn ambiguous_input() {
+        let cases: &[(&[u8], bool)] = &[
+            (b"", false),
+            (b"\x1b", true),
+            (b"\x1b[", true),
+            (b"\x1bO", true),
+            (b"\x1b[1", false),
+            (b"\x1b[1;5", false),
+            (&"é".as_bytes()[..1], false),
+            (b"\x1b[200~", false),
+        ];
+        for &(bytes, ambiguous) in cases {
+            let mut decoder = KeyDecoder::new();
+            for &byte in bytes {
+                decoder.push(byte);
+            }
+            assert_eq!(decoder.is_ambiguous(), ambiguous, "{bytes:?}");
+        }
+    }
+}
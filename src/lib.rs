@@ -40,81 +40,184 @@
 #![allow(clippy::manual_div_ceil)]
 #![allow(clippy::manual_range_contains)]
 
+use std::borrow::Cow;
 use std::cmp::min;
 use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
 use std::os::unix::io::RawFd;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{env, mem};
 
-use libc::{c_void, tcgetattr, tcsetattr, termios};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+mod error;
+mod keys;
+mod screen;
+mod style;
+mod terminal;
+mod vi;
+
+pub use error::Error;
+use keys::{Binding, Input, KeyDecoder, Keymap};
+pub use keys::{Cmd, KeyCode, KeyEvent, Modifiers};
+pub use screen::{Cell, Screen};
+use style::{strip_ansi, visible_width};
+pub use style::{Color, Hint, PromptBuilder, Span, Style};
+use terminal::FdTerminal;
+pub use terminal::{HeadlessTerminal, TerminalBackend};
+use vi::{ViMode, ViState};
 
 // Constants
 const LINENOISE_DEFAULT_HISTORY_MAX_LEN: usize = 100;
 const LINENOISE_MAX_LINE: usize = 4096;
-
-// Key codes
-#[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum Key {
-    CtrlA = 1,
-    CtrlB = 2,
-    CtrlC = 3,
-    CtrlD = 4,
-    CtrlE = 5,
-    CtrlF = 6,
-    CtrlH = 8,
-    Tab = 9,
-    CtrlK = 11,
-    CtrlL = 12,
-    Enter = 13,
-    CtrlN = 14,
-    CtrlP = 16,
-    CtrlT = 20,
-    CtrlU = 21,
-    CtrlW = 23,
-    Esc = 27,
-    Backspace = 127,
-}
+const LINENOISE_DEFAULT_COMPLETION_QUERY_ITEMS: usize = 100;
+const LINENOISE_MENU_MAX_ROWS: usize = 10;
+const LINENOISE_KILL_RING_MAX_LEN: usize = 32;
+const LINENOISE_DEFAULT_ESC_TIMEOUT: Duration = Duration::from_millis(100);
+/// First line of history files with escaped entries. Without it, each
+/// line of the file is an entry as it is.
+const LINENOISE_HISTORY_ESCAPED: &str = "#linenoise-history escaped";
 
 // Callback types
 pub type CompletionCallback = fn(&str, &mut Vec<String>);
+pub type CompleterCallback = Box<dyn FnMut(&str, usize, &mut Vec<Candidate>) + Send>;
 pub type HintsCallback = fn(&str) -> Option<(String, i32, bool)>;
+pub type HinterCallback = Box<dyn FnMut(&str) -> Option<Hint> + Send>;
+pub type HighlighterCallback = Box<dyn FnMut(&str, usize) -> Vec<Span> + Send>;
+pub type ValidatorCallback = Box<dyn FnMut(&str) -> Validation + Send>;
+pub type PasteFilterCallback = Box<dyn FnMut(&str) -> String + Send>;
+pub type KeyCallback = Box<dyn FnMut(&mut String, &mut usize) + Send>;
+pub type WordCharsCallback = Box<dyn Fn(char) -> bool + Send>;
+
+/// A callback held by a context and the copies lent out of it.
+type Shared<T> = Arc<Mutex<T>>;
 
 lazy_static::lazy_static! {
-    static ref G: Mutex<GlobalState> = Mutex::new(GlobalState::new());
+    /// Default instance used by the `linenoise_*` free functions.
+    static ref G: Mutex<Linenoise> = Mutex::new(Linenoise::new());
 }
 
-struct GlobalState {
+/// Settings, history and callbacks shared by every prompt of one
+/// [`Linenoise`] instance.
+#[derive(Clone)]
+struct Context {
     /// Multi-line mode. Default is single line.
     multi_line: bool,
     /// Show "***" instead of input. For passwords.
     mask_mode: bool,
-    /// Input history.
-    history: History,
+    /// Emacs or vi key bindings.
+    edit_mode: EditMode,
+    /// Key bindings, of the Emacs mode and the vi insert mode.
+    keymap: Keymap,
+    /// Which characters make up words, for word movement and editing.
+    word_chars: Shared<WordCharsCallback>,
+    /// How long to wait for the rest of an escape sequence before taking
+    /// Esc as a key of its own.
+    esc_timeout: Duration,
+    /// Have the terminal mark pasted text, to insert it as a whole.
+    bracketed_paste: bool,
+    /// What is done with the line breaks in pasted text.
+    paste_newlines: PasteNewlines,
+    /// Callback rewriting pasted text before it is inserted.
+    paste_filter: Option<Shared<PasteFilterCallback>>,
+    /// Input history, copied when changed while lent out.
+    history: Arc<History>,
+    /// Up/Down only recall entries starting with the typed prefix.
+    history_prefix_search: bool,
+    /// Suggest the rest of the line from history, fish style.
+    autosuggest: bool,
+    /// Killed text, shared by every prompt.
+    kill_ring: KillRing,
     /// Callback for showing input completion.
-    completion_callback: Option<CompletionCallback>,
+    completer: Option<Shared<CompleterCallback>>,
+    /// How candidates are presented on Tab.
+    completion_mode: CompletionMode,
+    /// Ask before listing more than this many candidates.
+    completion_query_items: usize,
+    /// First Tab inserts the longest common prefix of the candidates.
+    completion_prefix: bool,
+    /// Ignore case when computing the common prefix.
+    completion_ignore_case: bool,
     /// Callback for showing input hints.
-    hints_callback: Option<HintsCallback>,
-    /// For `atexit()` to check if restore is needed.
-    raw_mode: bool,
-    /// In order to restore at exit.
-    orig_termios: Option<termios>,
+    hinter: Option<Shared<HinterCallback>>,
+    /// Callback styling the line as it is typed.
+    highlighter: Option<Shared<HighlighterCallback>>,
+    /// Callback deciding whether Enter submits the line.
+    validator: Option<Shared<ValidatorCallback>>,
+    /// Prompt of the lines after the first of a multi-line input.
+    continuation_prompt: String,
 }
 
-impl GlobalState {
+impl Context {
     fn new() -> Self {
-        GlobalState {
+        Context {
             multi_line: false,
             mask_mode: false,
-            history: History::new(),
-            completion_callback: None,
-            hints_callback: None,
-            raw_mode: false,
-            orig_termios: None,
+            edit_mode: EditMode::Emacs,
+            keymap: Keymap::new(),
+            word_chars: Arc::new(Mutex::new(Box::new(|c| !c.is_whitespace()))),
+            esc_timeout: LINENOISE_DEFAULT_ESC_TIMEOUT,
+            bracketed_paste: true,
+            paste_newlines: PasteNewlines::Insert,
+            paste_filter: None,
+            history: Arc::new(History::new()),
+            history_prefix_search: false,
+            autosuggest: false,
+            kill_ring: KillRing::new(),
+            completer: None,
+            completion_mode: CompletionMode::Circular,
+            completion_query_items: LINENOISE_DEFAULT_COMPLETION_QUERY_ITEMS,
+            completion_prefix: false,
+            completion_ignore_case: false,
+            hinter: None,
+            highlighter: None,
+            validator: None,
+            continuation_prompt: String::from("... "),
         }
     }
+
+    /// A copy of the context to edit with, sharing its settings, history
+    /// and callbacks. The kill ring goes with it until it is given back.
+    fn lend(&mut self) -> Context {
+        Context {
+            kill_ring: mem::replace(&mut self.kill_ring, KillRing::new()),
+            ..self.clone()
+        }
+    }
+
+    /// Take back what a context lent with [`lend`](Self::lend) changed.
+    fn give_back(&mut self, lent: Context) {
+        self.kill_ring = lent.kill_ring;
+    }
+}
+
+/// How an edit session gets at the context of its instance.
+trait ContextAccess {
+    fn with<R>(&mut self, f: impl FnOnce(&mut Context) -> R) -> R;
+}
+
+impl ContextAccess for Context {
+    fn with<R>(&mut self, f: impl FnOnce(&mut Context) -> R) -> R {
+        f(self)
+    }
+}
+
+/// The context of the default instance, lent out of it each time it is
+/// used so that its lock isn't held while waiting for input or calling
+/// back. The callbacks can then use the `linenoise_*` functions.
+struct DefaultContext;
+
+impl ContextAccess for DefaultContext {
+    fn with<R>(&mut self, f: impl FnOnce(&mut Context) -> R) -> R {
+        let mut ctx = G.lock().unwrap().ctx.lend();
+        let result = f(&mut ctx);
+        G.lock().unwrap().ctx.give_back(ctx);
+        result
+    }
 }
 
 // History management
@@ -158,246 +261,119 @@ impl History {
     }
 }
 
-// Terminal handling
-struct Terminal {
-    ifd: RawFd,
-    ofd: RawFd,
-    cols: usize,
-}
-
-/// RAII guard that restores terminal to original mode when dropped
-struct RawModeGuard {
-    ifd: RawFd,
-    orig_termios: termios,
-}
-
-impl Drop for RawModeGuard {
-    fn drop(&mut self) {
-        unsafe {
-            tcsetattr(self.ifd, libc::TCSAFLUSH, &self.orig_termios);
+/// Undo the escaping of line breaks and backslashes in a line of a
+/// history file. Other backslashes are left alone.
+fn unescape_history_line(line: &str) -> String {
+    let mut entry = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            entry.push(c);
+            continue;
         }
-        // Also update global state
-        if let Ok(mut state) = G.lock() {
-            state.raw_mode = false;
-            state.orig_termios = None;
+        match chars.next() {
+            Some('n') => entry.push('\n'),
+            Some('\\') => entry.push('\\'),
+            Some(c) => {
+                entry.push('\\');
+                entry.push(c);
+            }
+            None => entry.push('\\'),
         }
     }
+    entry
 }
 
-impl Terminal {
-    fn new(ifd: RawFd, ofd: RawFd) -> Self {
-        let cols = Self::get_columns(ifd, ofd);
-        Terminal { ifd, ofd, cols }
-    }
-
-    /// Raw mode: 1960 magic shit.
-    fn enable_raw_mode(&self) -> io::Result<RawModeGuard> {
-        if !self.is_tty() {
-            return Err(io::Error::other("Not a TTY"));
-        }
-
-        let mut orig = unsafe { mem::zeroed::<termios>() };
-        if unsafe { tcgetattr(self.ifd, &mut orig) } == -1 {
-            return Err(io::Error::last_os_error());
-        }
-
-        // Modify the original mode
-        let mut raw = orig;
-        // input modes: no break, no CR to NL, no parity check, no strip char,
-        // no start/stop output control
-        raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
-        // output modes - disable post processing
-        raw.c_oflag &= !libc::OPOST;
-        // control modes - set 8 bit chars
-        raw.c_cflag |= libc::CS8;
-        // local modes - echoing off, canonical off, no extended functions,
-        // no signal chars (^Z,^C)
-        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
-        // control chars - set return condition: min number of bytes and timer.
-        // We want read to return every single byte, without timeout
-        raw.c_cc[libc::VMIN] = 1; // 1 byte
-        raw.c_cc[libc::VTIME] = 0; // no timer
-
-        if unsafe { tcsetattr(self.ifd, libc::TCSAFLUSH, &raw) } < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        let mut state = G.lock().unwrap();
-        state.orig_termios = Some(orig);
-        state.raw_mode = true;
-        drop(state);
-
-        Ok(RawModeGuard {
-            ifd: self.ifd,
-            orig_termios: orig,
-        })
-    }
-
-    fn is_tty(&self) -> bool {
-        unsafe { libc::isatty(self.ifd) != 0 }
-    }
-
-    fn write(&self, s: &str) -> io::Result<()> {
-        self.write_bytes(s.as_bytes())
-    }
+/// Emacs style kill ring. Killed text is pushed on it and can be yanked
+/// back, most recent first.
+#[derive(Clone)]
+struct KillRing {
+    max_len: usize,
+    entries: VecDeque<String>,
+}
 
-    fn write_bytes(&self, buf: &[u8]) -> io::Result<()> {
-        let mut written = 0;
-        while written < buf.len() {
-            match unsafe {
-                libc::write(
-                    self.ofd,
-                    buf[written..].as_ptr() as *const c_void,
-                    buf.len() - written,
-                )
-            } {
-                -1 => {
-                    let err = io::Error::last_os_error();
-                    if err.kind() != io::ErrorKind::Interrupted {
-                        return Err(err);
-                    }
-                }
-                0 => break,
-                n => written += n as usize,
-            }
-        }
-        if self.ofd == libc::STDOUT_FILENO {
-            io::stdout().flush()?;
+impl KillRing {
+    fn new() -> Self {
+        KillRing {
+            max_len: LINENOISE_KILL_RING_MAX_LEN,
+            entries: VecDeque::new(),
         }
-        Ok(())
     }
 
-    fn read_byte(&self) -> io::Result<Option<u8>> {
-        let mut c = 0u8;
-        loop {
-            let n = unsafe { libc::read(self.ifd, &mut c as *mut u8 as *mut c_void, 1) };
-            if n == -1 {
-                let err = io::Error::last_os_error();
-                if err.kind() == io::ErrorKind::Interrupted {
-                    continue;
-                }
-                return Err(err);
+    /// Push killed text. With `append` it is merged into the latest entry
+    /// instead, in front of it if it was killed backwards.
+    fn kill(&mut self, text: &str, append: bool, backward: bool) {
+        if let Some(last) = self.entries.back_mut().filter(|_| append) {
+            if backward {
+                last.insert_str(0, text);
+            } else {
+                last.push_str(text);
             }
-            return Ok(if n == 0 { None } else { Some(c) });
+            return;
         }
-    }
-
-    fn read_byte_nonblocking(&self) -> io::Result<Option<u8>> {
-        use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
-
-        unsafe {
-            // Get current flags
-            let flags = fcntl(self.ifd, F_GETFL, 0);
-            if flags == -1 {
-                return Err(io::Error::last_os_error());
-            }
-
-            // Set non-blocking
-            if fcntl(self.ifd, F_SETFL, flags | O_NONBLOCK) == -1 {
-                return Err(io::Error::last_os_error());
-            }
-
-            // Try to read
-            let result = self.read_byte();
-
-            // Restore blocking mode - always attempt this
-            let restore_result = fcntl(self.ifd, F_SETFL, flags);
 
-            // Check restore result after we have our read result
-            if restore_result == -1 {
-                return Err(io::Error::last_os_error());
-            }
-
-            match result {
-                Ok(b) => Ok(b),
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
-                Err(e) => Err(e),
-            }
+        if self.entries.len() >= self.max_len {
+            self.entries.pop_front();
         }
+        self.entries.push_back(text.to_string());
     }
 
-    /// Use the ESC [6n escape sequence to query the horizontal cursor position
-    /// and return it.
-    fn get_cursor_position(&self) -> io::Result<(usize, usize)> {
-        self.write_bytes(b"\x1b[6n")?;
-
-        let mut buf = [0u8; 32];
-        let mut i = 0;
-
-        while i < buf.len() - 1 {
-            buf[i] = self.read_byte()?.ok_or_else(|| {
-                io::Error::new(io::ErrorKind::UnexpectedEof, "EOF reading cursor")
-            })?;
-            i += 1;
-            if buf[i - 1] == b'R' {
-                break;
-            }
-        }
-
-        // Parse response
-        let response =
-            std::str::from_utf8(&buf[2..i - 1]).map_err(|_| io::Error::other("Invalid UTF-8"))?;
-
-        let (rows, cols) = response
-            .split_once(';')
-            .ok_or_else(|| io::Error::other("Invalid format"))?;
-
-        Ok((
-            rows.parse().map_err(|_| io::Error::other("Invalid row"))?,
-            cols.parse().map_err(|_| io::Error::other("Invalid col"))?,
-        ))
+    /// Get the `index`-th most recent entry, 0 being the latest.
+    fn get(&self, index: usize) -> Option<&str> {
+        self.entries
+            .get(self.entries.len().checked_sub(index + 1)?)
+            .map(|s| s.as_str())
     }
+}
 
-    /// Try to get the number of columns in the current terminal, or assume 80
-    /// if it fails.
-    fn get_columns(ifd: RawFd, ofd: RawFd) -> usize {
-        // First try with ioctl
-        unsafe {
-            let mut ws: libc::winsize = mem::zeroed();
-            if libc::ioctl(1, libc::TIOCGWINSZ, &mut ws) == 0 && ws.ws_col != 0 {
-                return ws.ws_col as usize;
-            }
-        }
-
-        // ioctl() failed. Try to query the terminal itself.
-        // This is the fallback method from the original linenoise.
+// Terminal handling
 
-        // We need to create a temporary terminal to use its methods
-        let temp_terminal = Terminal { ifd, ofd, cols: 80 };
+/// The terminal of an edit session, and its width as of the last refresh.
+struct Terminal {
+    backend: Box<dyn TerminalBackend>,
+    cols: usize,
+}
 
-        // Get the initial position so we can restore it later
-        let orig_pos = match temp_terminal.get_cursor_position() {
-            Ok(pos) => pos,
-            Err(_) => return 80,
-        };
+impl Terminal {
+    fn new(mut backend: Box<dyn TerminalBackend>) -> Self {
+        let cols = backend.columns();
+        Terminal { backend, cols }
+    }
 
-        // Go to right margin and get position
-        if temp_terminal.write_bytes(b"\x1b[999C").is_err() {
-            return 80;
-        }
+    fn write(&mut self, s: &str) -> io::Result<()> {
+        self.backend.write(s.as_bytes())
+    }
 
-        let cols = match temp_terminal.get_cursor_position() {
-            Ok(pos) => pos.1,
-            Err(_) => 80,
-        };
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.backend.read(buf)
+    }
 
-        // Restore position
-        if orig_pos != (0, 0) {
-            let _ = temp_terminal.write(&format!("\x1b[{};{}H", orig_pos.0, orig_pos.1));
-        }
+    fn wait_input(&mut self, timeout: Duration) -> io::Result<bool> {
+        self.backend.wait_input(timeout)
+    }
 
-        cols
+    /// Update the columns in case of resize.
+    fn update_columns(&mut self) {
+        self.cols = self.backend.columns();
     }
 
-    fn clear_screen(&self) -> io::Result<()> {
+    fn clear_screen(&mut self) -> io::Result<()> {
         self.write("\x1b[H\x1b[2J")
     }
 
-    fn beep(&self) {
+    fn beep(&mut self) {
         let _ = self.write("\x07");
     }
 }
 
+/// Case change applied to a word by Alt-U, Alt-L and Alt-C.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WordCase {
+    Upper,
+    Lower,
+    Capitalize,
+}
+
 // Line buffer for editing
 struct LineBuffer {
     chars: Vec<char>,
@@ -423,7 +399,8 @@ impl LineBuffer {
 
     fn delete(&mut self) -> bool {
         if self.pos < self.chars.len() {
-            self.chars.remove(self.pos);
+            let end = next_grapheme(&self.chars, self.pos);
+            self.chars.drain(self.pos..end);
             true
         } else {
             false
@@ -432,8 +409,9 @@ impl LineBuffer {
 
     fn backspace(&mut self) -> bool {
         if self.pos > 0 {
-            self.pos -= 1;
-            self.chars.remove(self.pos);
+            let start = prev_grapheme(&self.chars, self.pos);
+            self.chars.drain(start..self.pos);
+            self.pos = start;
             true
         } else {
             false
@@ -442,7 +420,7 @@ impl LineBuffer {
 
     fn move_left(&mut self) -> bool {
         if self.pos > 0 {
-            self.pos -= 1;
+            self.pos = prev_grapheme(&self.chars, self.pos);
             true
         } else {
             false
@@ -451,44 +429,233 @@ impl LineBuffer {
 
     fn move_right(&mut self) -> bool {
         if self.pos < self.chars.len() {
-            self.pos += 1;
+            self.pos = next_grapheme(&self.chars, self.pos);
             true
         } else {
             false
         }
     }
 
+    /// Swap the grapheme cluster before the cursor with the one at it, or
+    /// the last two at the end of the line, leaving the cursor after them.
+    fn transpose_chars(&mut self) -> bool {
+        let len = self.chars.len();
+        let mid = if self.pos == len {
+            prev_grapheme(&self.chars, len)
+        } else {
+            self.pos
+        };
+        if mid == 0 {
+            return false;
+        }
+
+        let start = prev_grapheme(&self.chars, mid);
+        let end = next_grapheme(&self.chars, mid);
+        self.chars[start..end].rotate_left(mid - start);
+        self.pos = end;
+        true
+    }
+
     fn move_home(&mut self) {
-        self.pos = 0;
+        self.pos = self.line_start(self.pos);
     }
 
     fn move_end(&mut self) {
-        self.pos = self.chars.len();
+        self.pos = self.line_end(self.pos);
     }
 
-    fn delete_to_end(&mut self) {
-        self.chars.truncate(self.pos);
+    /// The start of the line of a multi-line buffer that `pos` is in.
+    fn line_start(&self, pos: usize) -> usize {
+        self.chars[..pos]
+            .iter()
+            .rposition(|&c| c == '\n')
+            .map_or(0, |i| i + 1)
     }
 
-    fn delete_word(&mut self) {
-        let start = self.pos;
+    /// The end of the line of a multi-line buffer that `pos` is in, before
+    /// its line break.
+    fn line_end(&self, pos: usize) -> usize {
+        self.chars[pos..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(self.chars.len(), |i| pos + i)
+    }
+
+    /// Move to the line above in a multi-line buffer, keeping the column.
+    /// Returns false on the first line.
+    fn move_line_up(&mut self) -> bool {
+        let start = self.line_start(self.pos);
+        if start == 0 {
+            return false;
+        }
+        let column = char_widths(&self.chars[start..self.pos]).iter().sum();
+        self.pos = self.position_at_column(self.line_start(start - 1), column);
+        true
+    }
+
+    /// Move to the line below in a multi-line buffer, keeping the column.
+    /// Returns false on the last line.
+    fn move_line_down(&mut self) -> bool {
+        let end = self.line_end(self.pos);
+        if end == self.chars.len() {
+            return false;
+        }
+        let start = self.line_start(self.pos);
+        let column = char_widths(&self.chars[start..self.pos]).iter().sum();
+        self.pos = self.position_at_column(end + 1, column);
+        true
+    }
+
+    /// The grapheme boundary in the line starting at `start` that is at
+    /// `column`, or before it if a wide character is there, or at the
+    /// line's end.
+    fn position_at_column(&self, start: usize, column: usize) -> usize {
+        let line = &self.chars[start..self.line_end(start)];
+        let widths = char_widths(line);
+        let mut pos = 0;
+        let mut used = 0;
+        for next in grapheme_boundaries(line).into_iter().skip(1) {
+            let width: usize = widths[pos..next].iter().sum();
+            if used + width > column {
+                break;
+            }
+            used += width;
+            pos = next;
+        }
+        start + pos
+    }
+
+    /// The grapheme cluster boundaries of the buffer, whether each cluster
+    /// is a word character by its first character, and the index of the
+    /// cluster the cursor is in.
+    fn word_clusters(&self, is_word: &dyn Fn(char) -> bool) -> (Vec<usize>, Vec<bool>, usize) {
+        let boundaries = grapheme_boundaries(&self.chars);
+        let words = boundaries[..boundaries.len() - 1]
+            .iter()
+            .map(|&b| is_word(self.chars[b]))
+            .collect();
+        let at = boundaries.iter().rposition(|&b| b <= self.pos).unwrap_or(0);
+        (boundaries, words, at)
+    }
+
+    fn move_word_left(&mut self, is_word: &dyn Fn(char) -> bool) {
+        let (boundaries, words, mut i) = self.word_clusters(is_word);
+
+        // Skip separators
+        while i > 0 && !words[i - 1] {
+            i -= 1;
+        }
+
+        // Skip word
+        while i > 0 && words[i - 1] {
+            i -= 1;
+        }
+        self.pos = boundaries[i];
+    }
 
-        // Skip spaces
-        while self.pos > 0 && self.chars[self.pos - 1] == ' ' {
-            self.pos -= 1;
+    fn move_word_right(&mut self, is_word: &dyn Fn(char) -> bool) {
+        let (boundaries, words, mut i) = self.word_clusters(is_word);
+
+        // Skip separators
+        while i < words.len() && !words[i] {
+            i += 1;
         }
 
         // Skip word
-        while self.pos > 0 && self.chars[self.pos - 1] != ' ' {
-            self.pos -= 1;
+        while i < words.len() && words[i] {
+            i += 1;
+        }
+        self.pos = boundaries[i];
+    }
+
+    /// Delete to the end of the line, or the line break at it.
+    fn delete_to_end(&mut self) -> String {
+        let end = match self.line_end(self.pos) {
+            end if end == self.pos && end < self.chars.len() => end + 1,
+            end => end,
+        };
+        self.chars.drain(self.pos..end).collect()
+    }
+
+    fn delete_word(&mut self, is_word: &dyn Fn(char) -> bool) -> String {
+        let start = self.pos;
+        self.move_word_left(is_word);
+        self.chars.drain(self.pos..start).collect()
+    }
+
+    fn delete_word_right(&mut self, is_word: &dyn Fn(char) -> bool) -> String {
+        let start = self.pos;
+        self.move_word_right(is_word);
+        let end = mem::replace(&mut self.pos, start);
+        self.chars.drain(start..end).collect()
+    }
+
+    /// Swap the word before the cursor with the one after it, or the last
+    /// two words at the end of the line, leaving the cursor after them.
+    fn transpose_words(&mut self, is_word: &dyn Fn(char) -> bool) -> bool {
+        let orig = self.pos;
+        self.move_word_right(is_word);
+        let w2_end = self.pos;
+        self.move_word_left(is_word);
+        let w2_start = self.pos;
+        self.move_word_left(is_word);
+        let w1_start = self.pos;
+        self.move_word_right(is_word);
+        let w1_end = self.pos;
+
+        if w1_start == w2_start || w2_start < w1_end {
+            self.pos = orig;
+            return false;
         }
 
-        self.chars.drain(self.pos..start);
+        let mut swapped = self.chars[w2_start..w2_end].to_vec();
+        swapped.extend_from_slice(&self.chars[w1_end..w2_start]);
+        swapped.extend_from_slice(&self.chars[w1_start..w1_end]);
+        self.chars.splice(w1_start..w2_end, swapped);
+        self.pos = w2_end;
+        true
+    }
+
+    /// Change the case of the rest of the word after the cursor, moving
+    /// past it.
+    fn change_word_case(&mut self, is_word: &dyn Fn(char) -> bool, case: WordCase) {
+        let (boundaries, words, start) = self.word_clusters(is_word);
+        self.move_word_right(is_word);
+        let mut first = true;
+        for (i, range) in boundaries.windows(2).enumerate().skip(start) {
+            if range[0] >= self.pos {
+                break;
+            }
+            let upper = match case {
+                WordCase::Upper => true,
+                WordCase::Lower => false,
+                WordCase::Capitalize => first,
+            };
+            if words[i] {
+                for c in &mut self.chars[range[0]..range[1]] {
+                    *c = change_case(*c, upper);
+                }
+            }
+            if self.chars[range[0]].is_alphanumeric() {
+                first = false;
+            }
+        }
     }
 
-    fn clear(&mut self) {
-        self.chars.clear();
+    fn clear(&mut self) -> String {
         self.pos = 0;
+        self.chars.drain(..).collect()
+    }
+
+    /// Replace the characters in `range` with `s` and leave the cursor
+    /// right after the inserted text.
+    fn replace(&mut self, range: Range<usize>, s: &str) {
+        let end = min(range.end, self.chars.len());
+        let start = min(range.start, end);
+        let room = (LINENOISE_MAX_LINE - 1).saturating_sub(self.chars.len() - (end - start));
+        let inserted: Vec<char> = s.chars().take(room).collect();
+        self.pos = start + inserted.len();
+        self.chars.splice(start..end, inserted);
     }
 
     fn set(&mut self, s: &str) {
@@ -507,25 +674,235 @@ struct Editor {
     prompt: String,
     history_index: usize,
     saved_line: Option<String>,
+    /// Text before the cursor when history navigation started.
+    history_prefix: String,
     completion_state: Option<CompletionState>,
+    /// Candidates waiting for a "Display all N possibilities?" answer.
+    completion_query: Option<Vec<Candidate>>,
     old_rows: usize,          // For multiline mode
     cursor_row_offset: usize, // For multiline mode
+    menu_rows: usize,         // Rows of completion menu below the line
+    search: Option<SearchState>,
+    undo: UndoStack,
+    /// What the key being processed did to the line, for undo grouping.
+    edit_kind: EditKind,
+    /// Decodes input bytes into keys.
+    decoder: KeyDecoder,
+    /// Keys typed so far of a bound key sequence.
+    pending_keys: Vec<KeyEvent>,
+    /// What the key being processed did with the kill ring.
+    kill_action: KillAction,
+    /// What the previous key did with the kill ring.
+    last_kill_action: KillAction,
+    /// Vi mode state, unused with Emacs key bindings.
+    vi: ViState,
+    /// Message of a validator rejecting the input, shown until the next key.
+    validation_error: Option<String>,
+    /// Input is being processed in a batch, redrawn once at its end.
+    batching: bool,
+    /// A redraw was put off until the end of the batch.
+    needs_refresh: bool,
+    /// Input read past the end of the line.
+    leftover: Leftover,
+}
+
+/// Input read past the end of a line, for the next line edited on the
+/// same terminal.
+#[derive(Default)]
+struct Leftover {
+    /// The rest of a paste submitted line by line.
+    paste: String,
+    /// Input that came after the end of the line.
+    input: Vec<u8>,
+}
+
+/// Kill ring use of a key, so consecutive kills can be merged and a yank
+/// can be replaced by an older entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KillAction {
+    None,
+    Kill,
+    /// Yanked `len` characters at `start`, from kill ring entry `index`.
+    Yank {
+        start: usize,
+        len: usize,
+        index: usize,
+    },
+}
+
+/// What kind of change a key made to the line. Consecutive changes of the
+/// same kind, other than [`EditKind::Other`], are undone in one step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EditKind {
+    Other,
+    Insert,
+    Complete,
+    History,
+    Search,
+    /// Undo or redo itself, never recorded.
+    Undo,
+}
+
+/// Undo and redo stacks of line snapshots (characters and cursor).
+struct UndoStack {
+    undo: Vec<(Vec<char>, usize)>,
+    redo: Vec<(Vec<char>, usize)>,
+    /// Kind of the last recorded change, if the next one may be merged.
+    last_kind: Option<EditKind>,
+}
+
+impl UndoStack {
+    fn new() -> Self {
+        UndoStack {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            last_kind: None,
+        }
+    }
+
+    /// Record that a key of the given kind turned the line `before` into
+    /// `after`.
+    fn record(&mut self, before: (Vec<char>, usize), after: &[char], kind: EditKind) {
+        if kind == EditKind::Undo {
+            self.last_kind = None;
+            return;
+        }
+
+        if before.0 == after {
+            // Keep grouping through keys that change nothing, like a
+            // failed search, but not through cursor movement
+            if self.last_kind != Some(kind) {
+                self.last_kind = None;
+            }
+            return;
+        }
+
+        if kind == EditKind::Other || self.last_kind != Some(kind) {
+            self.undo.push(before);
+        }
+        self.redo.clear();
+        self.last_kind = (kind != EditKind::Other).then_some(kind);
+    }
+}
+
+/// Incremental history search started with Ctrl-R or Ctrl-S, or with `/`
+/// or `?` in vi normal mode.
+struct SearchState {
+    query: String,
+    forward: bool,
+    /// Started from vi normal mode, where Enter ends the search instead of
+    /// accepting the line.
+    vi: bool,
+    /// History index of the current match, 0 if nothing matched yet.
+    match_index: usize,
+    /// The last search found nothing.
+    failed: bool,
+    original_line: Vec<char>,
+    original_pos: usize,
 }
 
 struct CompletionState {
-    original_line: String,
+    original_line: Vec<char>,
+    original_pos: usize,
+    candidates: Vec<Candidate>,
     current_index: usize,
+    /// Candidates are shown in a menu below the line.
+    menu: bool,
+}
+
+/// Key bindings used for editing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EditMode {
+    /// Emacs style bindings, like readline's default.
+    #[default]
+    Emacs,
+    /// Vi style bindings. Each line starts in insert mode, which edits
+    /// like the Emacs mode; Esc switches to normal mode for vi commands.
+    Vi,
+}
+
+/// What feeding input to an edit session came to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FeedResult {
+    /// The line is still being edited; feed more input when there is some.
+    Pending,
+    /// Enter was pressed on this line.
+    Line(String),
+    /// Ctrl-D was pressed on an empty line, or there is no edit session.
+    Eof,
+    /// Ctrl-C was pressed.
+    Interrupted,
+}
+
+/// Whether the input is ready to be submitted, as decided by a validator
+/// callback when Enter is pressed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Validation {
+    /// Submit the input.
+    Complete,
+    /// Insert a line break and go on editing, e.g. after an unclosed
+    /// bracket.
+    Incomplete,
+    /// Don't submit the input, showing the message below it.
+    Invalid(String),
+}
+
+/// What is done with the line breaks in pasted text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PasteNewlines {
+    /// Insert them in the line, like the rest of the text.
+    #[default]
+    Insert,
+    /// Leave them out.
+    Strip,
+    /// Submit the line at each of them, as if Enter was typed, so a
+    /// validator can keep the input going. The text after the first one
+    /// goes on after that, in the following prompts once the line is
+    /// submitted.
+    Submit,
+}
+
+/// How Tab presents completion candidates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompletionMode {
+    /// Each Tab replaces the completed text with the next candidate.
+    #[default]
+    Circular,
+    /// A single candidate is inserted, several ones are listed in columns
+    /// below the prompt, bash style.
+    List,
+    /// Several candidates are shown in a menu below the line, navigable
+    /// with the arrows, Tab and Shift-Tab, zsh `menu-select` style. Enter
+    /// accepts the selection and Esc restores the original line.
+    Menu,
+}
+
+/// A completion candidate returned by a completer callback.
+///
+/// `range` is the span of the line, in characters, that gets replaced by
+/// `text` when the candidate is selected. The cursor is left right after
+/// the inserted text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+impl Candidate {
+    pub fn new(range: Range<usize>, text: impl Into<String>) -> Self {
+        Candidate {
+            range,
+            text: text.into(),
+        }
+    }
 }
 
 // Helper macro for common key processing pattern
 macro_rules! key_action {
-    ($self:expr, $action:expr) => {{
+    ($self:expr, $ctx:expr, $action:expr) => {{
         $action;
-        $self.refresh_line()?;
-        Err(io::Error::new(
-            io::ErrorKind::WouldBlock,
-            "More input needed",
-        ))
+        $self.refresh_line($ctx)?;
+        Ok(FeedResult::Pending)
     }};
 }
 
@@ -537,115 +914,407 @@ impl Editor {
             prompt: prompt.to_string(),
             history_index: 0,
             saved_line: None,
+            history_prefix: String::new(),
             completion_state: None,
+            completion_query: None,
             old_rows: 0,
             cursor_row_offset: 0,
+            menu_rows: 0,
+            search: None,
+            undo: UndoStack::new(),
+            edit_kind: EditKind::Other,
+            decoder: KeyDecoder::new(),
+            pending_keys: Vec::new(),
+            kill_action: KillAction::None,
+            last_kill_action: KillAction::None,
+            vi: ViState::new(),
+            validation_error: None,
+            batching: false,
+            needs_refresh: false,
+            leftover: Leftover::default(),
         }
     }
 
-    fn refresh_line(&mut self) -> io::Result<()> {
-        // Update terminal columns in case of resize
-        self.terminal.cols = Terminal::get_columns(self.terminal.ifd, self.terminal.ofd);
-
-        let state = G.lock().unwrap();
-
-        if state.multi_line {
-            self.refresh_multiline(&state)
-        } else {
-            self.refresh_singleline(&state)
+    /// Push killed text on the kill ring, appending to the previous kill
+    /// if the last key killed text too.
+    fn kill(&mut self, ctx: &mut Context, text: String, backward: bool) {
+        if !text.is_empty() {
+            let append = self.last_kill_action == KillAction::Kill;
+            ctx.kill_ring.kill(&text, append, backward);
         }
+        self.kill_action = KillAction::Kill;
     }
 
-    fn refresh_singleline(&mut self, state: &GlobalState) -> io::Result<()> {
-        let mut output = String::new();
-
-        // Move to start of line
-        output.push('\r');
+    /// Insert the most recently killed text at the cursor.
+    fn yank(&mut self, ctx: &mut Context) -> io::Result<()> {
+        let Some(text) = ctx.kill_ring.get(0) else {
+            self.terminal.beep();
+            return Ok(());
+        };
+        let start = self.buffer.pos;
+        self.buffer.replace(start..start, text);
+        self.kill_action = KillAction::Yank {
+            start,
+            len: self.buffer.pos - start,
+            index: 0,
+        };
+        self.refresh_line(ctx)
+    }
 
-        // Write prompt
-        output.push_str(&self.prompt);
+    /// Replace the text just yanked with the next older kill ring entry.
+    fn yank_pop(&mut self, ctx: &mut Context) -> io::Result<()> {
+        let KillAction::Yank { start, len, index } = self.last_kill_action else {
+            self.terminal.beep();
+            return Ok(());
+        };
+        let index = (index + 1) % ctx.kill_ring.entries.len();
+        let text = ctx.kill_ring.get(index).unwrap_or_default();
+        self.buffer.replace(start..start + len, text);
+        self.kill_action = KillAction::Yank {
+            start,
+            len: self.buffer.pos - start,
+            index,
+        };
+        self.refresh_line(ctx)
+    }
 
-        // Write buffer content
-        let content = if state.mask_mode {
-            "*".repeat(self.buffer.chars.len())
+    /// Undo the last change to the line, or redo the last undone one.
+    fn undo(&mut self, ctx: &mut Context, redo: bool) -> io::Result<()> {
+        self.edit_kind = EditKind::Undo;
+        let (from, to) = if redo {
+            (&mut self.undo.redo, &mut self.undo.undo)
         } else {
-            self.buffer.as_string()
+            (&mut self.undo.undo, &mut self.undo.redo)
         };
 
-        // Handle line that's too long
-        let prompt_len = self.prompt.chars().count();
-        let available_cols = self.terminal.cols.saturating_sub(prompt_len);
+        let Some((chars, pos)) = from.pop() else {
+            self.terminal.beep();
+            return Ok(());
+        };
+        to.push((mem::replace(&mut self.buffer.chars, chars), self.buffer.pos));
+        self.buffer.pos = pos;
+        self.refresh_line(ctx)
+    }
 
-        let cursor_screen_pos = if content.chars().count() > available_cols {
-            // Show a window around the cursor
-            let window_start = self.buffer.pos.saturating_sub(available_cols / 2);
-            let window_end = min(window_start + available_cols, content.chars().count());
-            let actual_window_start = window_end.saturating_sub(available_cols);
+    /// The prompt as currently displayed, which during incremental search
+    /// shows the search query instead.
+    fn display_prompt(&self) -> Cow<'_, str> {
+        match self.search {
+            Some(ref search) => Cow::Owned(format!(
+                "({}{}-i-search)'{}': ",
+                if search.failed { "failed " } else { "" },
+                if search.forward { "fwd" } else { "reverse" },
+                search.query
+            )),
+            None => Cow::Borrowed(&self.prompt),
+        }
+    }
 
-            let window: String = content
-                .chars()
-                .skip(actual_window_start)
-                .take(available_cols)
-                .collect();
-            output.push_str(&window);
+    /// The style of each character of the buffer from the highlighter, or
+    /// none at all without one and in mask mode.
+    fn content_styles(&self, ctx: &mut Context) -> Vec<Style> {
+        let mut styles = Vec::new();
+        if ctx.mask_mode {
+            return styles;
+        }
+        let Some(cb) = &ctx.highlighter else {
+            return styles;
+        };
 
-            // Calculate cursor position within the window
-            prompt_len + self.buffer.pos.saturating_sub(actual_window_start)
-        } else {
-            output.push_str(&content);
-
-            // Add hints if available (but not during completion)
-            if self.completion_state.is_none() {
-                if let Some(ref callback) = state.hints_callback {
-                    if let Some((hint, color, bold)) = callback(&self.buffer.as_string()) {
-                        let remaining = available_cols.saturating_sub(content.chars().count());
-                        if remaining > 0 {
-                            if bold {
-                                output.push_str("\x1b[1m");
-                            }
-                            if color >= 0 {
-                                output.push_str(&format!("\x1b[{color}m"));
-                            }
-                            let hint_truncated: String = hint.chars().take(remaining).collect();
-                            output.push_str(&hint_truncated);
-                            output.push_str("\x1b[0m");
-                        }
-                    }
-                }
+        let len = self.buffer.chars.len();
+        styles.resize(len, Style::new());
+        let spans = cb.lock().unwrap()(&self.buffer.as_string(), self.buffer.pos);
+        for span in spans {
+            let end = min(span.range.end, len);
+            if span.range.start < end {
+                styles[span.range.start..end].fill(span.style);
             }
+        }
+        styles
+    }
 
-            // When not windowing, cursor position is trivial
-            prompt_len + self.buffer.pos
+    /// Render the characters of the buffer in `range`, masked, or in
+    /// their `styles` with the search match in reverse video. Line breaks
+    /// start a new row with the continuation prompt.
+    fn render_content(&self, ctx: &Context, range: Range<usize>, styles: &[Style]) -> String {
+        let highlight = match self.search {
+            Some(ref search) if !search.failed && search.match_index > 0 => {
+                Some(self.buffer.pos..self.buffer.pos + search.query.chars().count())
+            }
+            _ => None,
         };
 
-        // Clear to end of line
-        output.push_str("\x1b[0K");
+        // Switch styles only where they change, starting and ending in the
+        // default one
+        let plain = (Style::new(), false);
+        let mut current = plain;
+        let mut output = String::new();
+        for i in range {
+            let c = self.buffer.chars[i];
+            if c == '\n' {
+                if current != plain {
+                    output.push_str("\x1b[0m");
+                    current = plain;
+                }
+                output.push_str("\r\n");
+                output.push_str(&ctx.continuation_prompt);
+                continue;
+            }
 
-        // Position cursor
-        output.push_str(&format!("\r\x1b[{cursor_screen_pos}C"));
+            let style = (
+                styles.get(i).copied().unwrap_or_default(),
+                highlight.as_ref().is_some_and(|h| h.contains(&i)),
+            );
+            if style != current {
+                if current != plain {
+                    output.push_str("\x1b[0m");
+                }
+                output.push_str(&style.0.prefix());
+                if style.1 {
+                    output.push_str("\x1b[7m");
+                }
+                current = style;
+            }
+            match c {
+                _ if ctx.mask_mode => output.push('*'),
+                c if c.is_ascii_control() => {
+                    output.push('^');
+                    output.push((c as u8 ^ 0x40) as char);
+                }
+                c => output.push(c),
+            }
+        }
+        if current != plain {
+            output.push_str("\x1b[0m");
+        }
+        output
+    }
 
-        self.terminal.write(&output)
+    /// The terminal columns taken by each character of the buffer as it
+    /// is rendered.
+    fn content_widths(&self, ctx: &Context) -> Vec<usize> {
+        if ctx.mask_mode {
+            vec![1; self.buffer.chars.len()]
+        } else {
+            char_widths(&self.buffer.chars)
+        }
     }
 
-    fn refresh_multiline(&mut self, state: &GlobalState) -> io::Result<()> {
-        let mut output = String::new();
-        let plen = self.prompt.chars().count();
-        let cols = self.terminal.cols;
+    /// The rest of the most recent history entry extending the line, up to
+    /// its next line break, when autosuggestions are enabled and the cursor
+    /// is at the end.
+    fn autosuggestion(&self, ctx: &Context) -> Option<String> {
+        if !ctx.autosuggest
+            || ctx.mask_mode
+            || self.buffer.chars.is_empty()
+            || self.buffer.pos != self.buffer.chars.len()
+            || self.completion_state.is_some()
+            || self.search.is_some()
+        {
+            return None;
+        }
 
-        // Calculate dimensions
-        let content_len = plen + self.buffer.chars.len();
-        let cursor_pos = plen + self.buffer.pos;
+        let line = self.buffer.as_string();
+        ctx.history
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| entry.len() > line.len() && entry.starts_with(&line))
+            .map(|entry| first_line(&entry[line.len()..]).to_string())
+            .filter(|suggestion| !suggestion.is_empty())
+    }
 
-        // Calculate how many rows we need
-        let content_rows = if content_len == 0 {
-            1
-        } else {
-            (content_len + cols - 1) / cols
+    /// Insert the autosuggestion, or just its next word. Returns false if
+    /// there is nothing to accept.
+    fn accept_suggestion(&mut self, ctx: &Context, word: bool) -> bool {
+        let Some(suggestion) = self.autosuggestion(ctx) else {
+            return false;
+        };
+
+        let text = if word {
+            let is_word = ctx.word_chars.lock().unwrap();
+            let is_word: &dyn Fn(char) -> bool = &**is_word;
+            let is_word = |g: &str| g.starts_with(is_word);
+            let end = suggestion
+                .grapheme_indices(true)
+                .skip_while(|&(_, g)| !is_word(g))
+                .find(|&(_, g)| !is_word(g))
+                .map_or(suggestion.len(), |(i, _)| i);
+            &suggestion[..end]
+        } else {
+            &suggestion
+        };
+
+        for c in text.chars() {
+            self.buffer.insert(c);
+        }
+        true
+    }
+
+    /// Render the autosuggestion, dimmed, followed by the user hint for
+    /// the line it suggests, truncated to `space` columns. A hint asking
+    /// to be shown below the line is returned separately instead, for the
+    /// row below. Nothing is shown during completion or search.
+    fn render_hints(&mut self, ctx: &mut Context, mut space: usize) -> (String, Option<String>) {
+        let mut output = String::new();
+        if self.completion_state.is_some() || self.search.is_some() {
+            return (output, None);
+        }
+
+        let mut line = self.buffer.as_string();
+        if let Some(suggestion) = self.autosuggestion(ctx) {
+            let shown = truncate_to_width(&suggestion, space);
+            if !shown.is_empty() {
+                space -= shown.width();
+                output.push_str(&format!("\x1b[2m{shown}\x1b[0m"));
+            }
+            line.push_str(&suggestion);
+        }
+
+        let Some(hint) = ctx.hinter.as_ref().and_then(|cb| cb.lock().unwrap()(&line)) else {
+            return (output, None);
+        };
+        let text = first_line(&hint.text);
+        if hint.below {
+            let shown = truncate_to_width(text, self.terminal.cols);
+            return (output, Some(hint.style.paint(shown)));
+        }
+        let shown = truncate_to_width(text, space);
+        if !shown.is_empty() {
+            output.push_str(&hint.style.paint(shown));
+        }
+        (output, None)
+    }
+
+    fn refresh_line(&mut self, ctx: &mut Context) -> io::Result<()> {
+        if self.batching {
+            self.needs_refresh = true;
+            return Ok(());
+        }
+
+        self.terminal.update_columns();
+
+        // A line break, or rows left from one, needs several rows
+        if ctx.multi_line || self.old_rows > 1 || self.buffer.chars.contains(&'\n') {
+            self.refresh_multiline(ctx)
+        } else {
+            self.refresh_singleline(ctx)
+        }
+    }
+
+    fn refresh_singleline(&mut self, ctx: &mut Context) -> io::Result<()> {
+        let mut output = String::new();
+
+        // Move to start of line
+        output.push('\r');
+
+        // Write prompt
+        let prompt = self.display_prompt();
+        output.push_str(&prompt);
+
+        // Handle line that's too long. The last column is left free: the
+        // cursor goes there at the end of the line, and after writing to it
+        // the clear to the end of the line would erase it.
+        let prompt_width = visible_width(&prompt);
+        let available_cols = self.terminal.cols.saturating_sub(prompt_width + 1);
+        let widths = self.content_widths(ctx);
+        let styles = self.content_styles(ctx);
+        let column = |i: usize| widths[..i].iter().sum::<usize>();
+        let len = widths.len();
+        let pos = self.buffer.pos;
+        let content_width = column(len);
+
+        let cursor_screen_pos = if content_width > available_cols {
+            // Show a window around the cursor, in whole characters
+            let end_col = min(
+                column(pos).saturating_sub(available_cols / 2) + available_cols,
+                content_width,
+            );
+            let start_col = end_col.saturating_sub(available_cols);
+
+            let mut start = 0;
+            let mut col = 0;
+            while start < pos && (col < start_col || widths[start] == 0) {
+                col += widths[start];
+                start += 1;
+            }
+            let mut end = start;
+            let mut used = 0;
+            while end < len && used + widths[end] <= available_cols {
+                used += widths[end];
+                end += 1;
+            }
+
+            output.push_str(&self.render_content(ctx, start..end, &styles));
+
+            // Calculate cursor position within the window
+            prompt_width + column(pos) - col
+        } else {
+            output.push_str(&self.render_content(ctx, 0..len, &styles));
+
+            // When not windowing, cursor position is trivial
+            prompt_width + column(pos)
         };
 
+        // Add hints if there is room for them
+        let remaining = available_cols.saturating_sub(content_width);
+        let (hints, hint_below) = self.render_hints(ctx, remaining);
+        output.push_str(&hints);
+
+        // Clear to end of line
+        output.push_str("\x1b[0K");
+
+        self.render_below(&mut output, 0, hint_below);
+
+        // Position cursor
+        output.push('\r');
+        if cursor_screen_pos > 0 {
+            output.push_str(&format!("\x1b[{cursor_screen_pos}C"));
+        }
+
+        self.terminal.write(&output)
+    }
+
+    fn refresh_multiline(&mut self, ctx: &mut Context) -> io::Result<()> {
+        let mut output = String::new();
+        let old_rows = self.old_rows;
+        let prompt = self.display_prompt().into_owned();
+        let cols = self.terminal.cols.max(1);
+        let widths = self.content_widths(ctx);
+        let pos = self.buffer.pos;
+
+        // Lay out the rows, with a character that doesn't fit at the end
+        // of one wrapped to the next like the terminal does
+        let mut row = 0;
+        let mut col = visible_width(&prompt);
+        while col > cols {
+            row += 1;
+            col -= cols;
+        }
+        let continuation_width = visible_width(&ctx.continuation_prompt);
+        let mut cursor = None;
+        for (i, &width) in widths.iter().enumerate() {
+            if self.buffer.chars[i] == '\n' {
+                if i == pos {
+                    cursor = Some((row, col));
+                }
+                row += 1 + continuation_width / cols;
+                col = continuation_width % cols;
+                continue;
+            }
+            if col + width > cols {
+                row += 1;
+                col = 0;
+            }
+            if i == pos {
+                cursor = Some((row, col));
+            }
+            col += width;
+        }
+        let content_rows = row + 1;
+
         // Do we need an extra row for cursor at end of line?
-        let phantom_line =
-            self.buffer.pos == self.buffer.chars.len() && cursor_pos > 0 && cursor_pos % cols == 0;
+        let phantom_line = cursor.is_none() && col == cols;
 
         let total_rows = if phantom_line {
             content_rows + 1
@@ -654,20 +1323,10 @@ impl Editor {
         };
 
         // Calculate where cursor should be
-        let cursor_row = if cursor_pos == 0 {
-            0
-        } else if phantom_line {
-            content_rows // 0-indexed, so this is the phantom line
-        } else {
-            (cursor_pos - 1) / cols
-        };
-
-        let cursor_col = if phantom_line {
-            0
-        } else if cursor_pos == 0 {
-            plen
-        } else {
-            (cursor_pos - 1) % cols + 1
+        let (cursor_row, cursor_col) = match cursor {
+            Some(cursor) => cursor,
+            None if phantom_line => (content_rows, 0),
+            None => (row, col),
         };
 
         // Move cursor to start of edit area
@@ -693,451 +1352,1780 @@ impl Editor {
         }
         output.push('\r');
 
-        // Write content
-        output.push_str(&self.prompt);
-        if state.mask_mode {
-            output.push_str(&"*".repeat(self.buffer.chars.len()));
-        } else {
-            output.push_str(&self.buffer.as_string());
-        }
+        // Write content
+        output.push_str(&prompt);
+        let styles = self.content_styles(ctx);
+        output.push_str(&self.render_content(ctx, 0..widths.len(), &styles));
+
+        // Add hints if appropriate
+        let space = if content_rows == 1 && !phantom_line {
+            cols - col
+        } else {
+            0
+        };
+        let (hints, hint_below) = self.render_hints(ctx, space);
+        output.push_str(&hints);
+
+        // Add phantom line if needed
+        if phantom_line {
+            output.push_str("\r\n");
+        }
+
+        self.render_below(&mut output, old_rows.saturating_sub(total_rows), hint_below);
+
+        // Now position cursor
+        // We're currently at end of content
+        let current_row = total_rows - 1;
+
+        // Move to cursor row
+        if cursor_row < current_row {
+            output.push_str(&format!("\x1b[{}A", current_row - cursor_row));
+        } else if cursor_row > current_row {
+            output.push_str(&format!("\x1b[{}B", cursor_row - current_row));
+        }
+
+        // Move to cursor column
+        output.push('\r');
+        if cursor_col > 0 {
+            output.push_str(&format!("\x1b[{cursor_col}C"));
+        }
+
+        // Update state
+        self.old_rows = total_rows;
+        self.cursor_row_offset = cursor_row;
+
+        self.terminal.write(&output)
+    }
+
+    /// Draw the hint shown below the line, if any, and the completion
+    /// menu on the rows below the current one and return to it, clearing
+    /// whatever was drawn there before. `stale_rows` are rows the edit area
+    /// no longer uses that the old rows were shifted into.
+    fn render_below(&mut self, output: &mut String, stale_rows: usize, hint: Option<String>) {
+        // A validation error takes the place of the hint
+        let error = self.validation_error.as_ref().map(|message| {
+            Style::new()
+                .fg(Color::Red)
+                .paint(truncate_to_width(message, self.terminal.cols))
+        });
+        let mut rows = Vec::from_iter(error.or(hint));
+
+        if let Some(state) = self.completion_state.as_ref().filter(|s| s.menu) {
+            let items: Vec<&str> = state.candidates.iter().map(|c| c.text.as_str()).collect();
+            let (per_row, width) = column_layout(&items, self.terminal.cols);
+            let total_rows = (items.len() + per_row - 1) / per_row;
+            let selected_row = state.current_index / per_row;
+            let first_row = min(
+                selected_row.saturating_sub(LINENOISE_MENU_MAX_ROWS - 1),
+                total_rows.saturating_sub(LINENOISE_MENU_MAX_ROWS),
+            );
+
+            for row in first_row..min(total_rows, first_row + LINENOISE_MENU_MAX_ROWS) {
+                let mut line = String::new();
+                for (i, item) in items.iter().enumerate().skip(row * per_row).take(per_row) {
+                    let cell = truncate_to_width(item, width);
+                    let pad = " ".repeat(width - cell.width());
+                    if i == state.current_index {
+                        line.push_str(&format!("\x1b[7m{cell}\x1b[0m{pad}"));
+                    } else {
+                        line.push_str(&format!("{cell}{pad}"));
+                    }
+                }
+                rows.push(line);
+            }
+        }
+
+        let to_draw = rows.len().max(self.menu_rows + stale_rows);
+        for i in 0..to_draw {
+            output.push_str("\r\n\x1b[2K");
+            if let Some(row) = rows.get(i) {
+                output.push_str(row);
+            }
+        }
+        if to_draw > 0 {
+            output.push_str(&format!("\x1b[{to_draw}A"));
+        }
+
+        self.menu_rows = rows.len();
+    }
+
+    /// Clear the hint and menu rows below the edit area and move the cursor
+    /// to its last row, before the line is done with.
+    fn clear_below(&mut self, ctx: &mut Context) -> io::Result<()> {
+        self.flush_refresh(ctx)?;
+        let below = self.old_rows.saturating_sub(self.cursor_row_offset + 1);
+        if below == 0 && self.menu_rows == 0 {
+            return Ok(());
+        }
+
+        let mut output = String::new();
+        if below > 0 {
+            output.push_str(&format!("\x1b[{below}B"));
+        }
+        for _ in 0..self.menu_rows {
+            output.push_str("\r\n\x1b[2K");
+        }
+        if self.menu_rows > 0 {
+            output.push_str(&format!("\x1b[{}A", self.menu_rows));
+        }
+        self.cursor_row_offset += below;
+        self.menu_rows = 0;
+        self.terminal.write(&output)
+    }
+
+    /// Do a redraw put off while batching, before writing anything else
+    /// that depends on what is on screen.
+    fn flush_refresh(&mut self, ctx: &mut Context) -> io::Result<()> {
+        if mem::take(&mut self.needs_refresh) {
+            let batching = mem::replace(&mut self.batching, false);
+            self.refresh_line(ctx)?;
+            self.batching = batching;
+        }
+        Ok(())
+    }
+
+    /// Move to a fresh row below the edit area. The prompt is redrawn
+    /// from there on the next refresh.
+    fn leave_edit_area(&mut self, ctx: &mut Context) -> io::Result<()> {
+        self.flush_refresh(ctx)?;
+        let mut output = String::new();
+        let below = self.old_rows.saturating_sub(self.cursor_row_offset + 1);
+        if below > 0 {
+            output.push_str(&format!("\x1b[{below}B"));
+        }
+        output.push_str("\r\n");
+        self.old_rows = 0;
+        self.cursor_row_offset = 0;
+        self.terminal.write(&output)
+    }
+
+    /// List all candidates in columns below the prompt, bash style.
+    fn list_candidates(&mut self, ctx: &mut Context, candidates: &[Candidate]) -> io::Result<()> {
+        let items: Vec<&str> = candidates.iter().map(|c| c.text.as_str()).collect();
+        let (per_row, width) = column_layout(&items, self.terminal.cols);
+        let rows = (items.len() + per_row - 1) / per_row;
+
+        // Fill columns top to bottom, like ls
+        let mut text = String::new();
+        for row in 0..rows {
+            let mut line = String::new();
+            for item in items.iter().skip(row).step_by(rows) {
+                let cell = truncate_to_width(item, width);
+                line.push_str(cell);
+                line.push_str(&" ".repeat(width - cell.width()));
+            }
+            text.push_str(line.trim_end());
+            text.push_str("\r\n");
+        }
+
+        self.leave_edit_area(ctx)?;
+        self.terminal.write(&text)?;
+        self.refresh_line(ctx)
+    }
+
+    /// Answer a pending "Display all N possibilities?" query.
+    fn answer_completion_query(
+        &mut self,
+        ctx: &mut Context,
+        candidates: Vec<Candidate>,
+        key: KeyEvent,
+    ) -> io::Result<()> {
+        if matches!(key.code, KeyCode::Char('y' | 'Y' | ' ')) && key.modifiers.is_empty() {
+            self.list_candidates(ctx, &candidates)
+        } else {
+            self.leave_edit_area(ctx)?;
+            self.refresh_line(ctx)
+        }
+    }
+
+    fn handle_completion(&mut self, ctx: &mut Context) -> io::Result<bool> {
+        self.edit_kind = EditKind::Complete;
+        let Some(cb) = &ctx.completer else {
+            return Ok(false);
+        };
+
+        if let Some(ref mut comp_state) = self.completion_state {
+            // Already in completion mode - cycle to next
+            comp_state.current_index = (comp_state.current_index + 1) % comp_state.candidates.len();
+            self.apply_candidate(ctx)?;
+            return Ok(true);
+        }
+
+        // First tab - ask for candidates at the cursor
+        let line = self.buffer.as_string();
+        let mut candidates = Vec::new();
+        cb.lock().unwrap()(&line, self.buffer.pos, &mut candidates);
+
+        if candidates.is_empty() {
+            self.terminal.beep();
+            return Ok(false);
+        }
+
+        if ctx.completion_prefix && self.insert_common_prefix(ctx, &candidates)? {
+            return Ok(true);
+        }
+
+        let mode = ctx.completion_mode;
+        if mode != CompletionMode::Circular && candidates.len() == 1 {
+            // Nothing to choose from, just insert it
+            let candidate = &candidates[0];
+            self.buffer
+                .replace(candidate.range.clone(), &candidate.text);
+            self.refresh_line(ctx)?;
+            return Ok(true);
+        }
+
+        if mode == CompletionMode::List {
+            if candidates.len() > ctx.completion_query_items {
+                let query = format!("Display all {} possibilities? (y or n)", candidates.len());
+                self.leave_edit_area(ctx)?;
+                self.terminal.write(&query)?;
+                self.completion_query = Some(candidates);
+            } else {
+                self.list_candidates(ctx, &candidates)?;
+            }
+            return Ok(true);
+        }
+
+        self.completion_state = Some(CompletionState {
+            original_line: self.buffer.chars.clone(),
+            original_pos: self.buffer.pos,
+            candidates,
+            current_index: 0,
+            menu: mode == CompletionMode::Menu,
+        });
+        self.apply_candidate(ctx)?;
+
+        Ok(true)
+    }
+
+    /// Replace the completed text with the longest common prefix of the
+    /// candidates. Returns false if that would not change anything, so
+    /// the candidates should be cycled or listed instead.
+    fn insert_common_prefix(
+        &mut self,
+        ctx: &mut Context,
+        candidates: &[Candidate],
+    ) -> io::Result<bool> {
+        // Only meaningful when all candidates replace the same text
+        let range = candidates[0].range.clone();
+        if candidates.iter().any(|c| c.range != range) {
+            return Ok(false);
+        }
+
+        let texts: Vec<&str> = candidates.iter().map(|c| c.text.as_str()).collect();
+        let prefix = common_prefix(&texts, ctx.completion_ignore_case);
+        let end = min(range.end, self.buffer.chars.len());
+        let start = min(range.start, end);
+        let current: String = self.buffer.chars[start..end].iter().collect();
+
+        // Never shorten what the user typed
+        if prefix == current || prefix.chars().count() < current.chars().count() {
+            return Ok(false);
+        }
+
+        self.buffer.replace(range, prefix);
+        self.refresh_line(ctx)?;
+        Ok(true)
+    }
+
+    /// Show the selected candidate on top of the original line.
+    fn apply_candidate(&mut self, ctx: &mut Context) -> io::Result<()> {
+        let Some(ref comp_state) = self.completion_state else {
+            return Ok(());
+        };
+        let candidate = &comp_state.candidates[comp_state.current_index];
+        self.buffer.chars.clone_from(&comp_state.original_line);
+        self.buffer.pos = comp_state.original_pos;
+        self.buffer
+            .replace(candidate.range.clone(), &candidate.text);
+        self.refresh_line(ctx)
+    }
+
+    fn accept_completion(&mut self, ctx: &mut Context) -> io::Result<()> {
+        let had_menu = self.completion_state.take().is_some_and(|s| s.menu);
+        if had_menu {
+            // Get rid of the menu
+            self.refresh_line(ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the line as it was before completion started.
+    fn cancel_completion(&mut self, ctx: &mut Context) -> io::Result<()> {
+        if let Some(comp_state) = self.completion_state.take() {
+            self.buffer.chars = comp_state.original_line;
+            self.buffer.pos = comp_state.original_pos;
+            self.refresh_line(ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Handle a key while the completion menu is shown. Returns false if
+    /// the key closed the menu and still needs to be processed.
+    fn handle_menu_key(&mut self, ctx: &mut Context, key: KeyEvent) -> io::Result<bool> {
+        self.edit_kind = EditKind::Complete;
+        let Some(ref mut comp_state) = self.completion_state else {
+            return Ok(false);
+        };
+        let len = comp_state.candidates.len();
+        let items: Vec<&str> = comp_state
+            .candidates
+            .iter()
+            .map(|c| c.text.as_str())
+            .collect();
+        let (per_row, _) = column_layout(&items, self.terminal.cols);
+        let index = comp_state.current_index;
+
+        let new_index = match (key.code, key.modifiers) {
+            (KeyCode::Tab, Modifiers::NONE) | (KeyCode::Right, _) => (index + 1) % len,
+            (KeyCode::Tab, _) | (KeyCode::Left, _) => (index + len - 1) % len,
+            (KeyCode::Down, _) if index + per_row < len => index + per_row,
+            (KeyCode::Up, _) if index >= per_row => index - per_row,
+            (KeyCode::Down | KeyCode::Up, _) => index,
+            (KeyCode::Enter, _) => {
+                self.accept_completion(ctx)?;
+                return Ok(true);
+            }
+            (KeyCode::Esc, _) => {
+                self.cancel_completion(ctx)?;
+                return Ok(true);
+            }
+            _ if key == KeyEvent::ctrl('g') => {
+                self.cancel_completion(ctx)?;
+                return Ok(true);
+            }
+            _ => {
+                self.accept_completion(ctx)?;
+                return Ok(false);
+            }
+        };
+
+        if let Some(ref mut comp_state) = self.completion_state {
+            comp_state.current_index = new_index;
+        }
+        self.apply_candidate(ctx)?;
+        Ok(true)
+    }
+
+    fn handle_history(&mut self, ctx: &mut Context, direction: isize) -> io::Result<()> {
+        self.edit_kind = EditKind::History;
+        let history_len = ctx.history.entries.len();
+
+        if history_len == 0 {
+            return Ok(());
+        }
+
+        // Save current line, and what was typed before the cursor, when
+        // starting to navigate
+        if self.history_index == 0 {
+            self.saved_line = Some(self.buffer.as_string());
+            self.history_prefix = self.buffer.chars[..self.buffer.pos].iter().collect();
+        }
+
+        // Update history index. With prefix search only stop at entries
+        // starting with the prefix, skipping the one already shown.
+        let filter = ctx.history_prefix_search && !self.history_prefix.is_empty();
+        let current = self.buffer.as_string();
+        let mut index = self.history_index;
+        loop {
+            if direction > 0 {
+                if index == history_len {
+                    // Nothing older matches, stay where we are
+                    index = self.history_index;
+                    break;
+                }
+                index += 1;
+            } else {
+                index = index.saturating_sub(1);
+            }
+            if index == 0 || !filter {
+                break;
+            }
+            let entry = ctx.history.get(index).unwrap_or_default();
+            if entry.starts_with(&self.history_prefix) && entry != current {
+                break;
+            }
+        }
+        self.history_index = index;
+
+        // Load history entry or restore saved line
+        if self.history_index == 0 {
+            if let Some(saved) = &self.saved_line {
+                self.buffer.set(saved);
+            }
+        } else if let Some(entry) = ctx.history.get(self.history_index) {
+            self.buffer.set(entry);
+        }
+
+        if filter {
+            // Keep the cursor right after the typed prefix
+            self.buffer.pos = self.history_prefix.chars().count();
+        }
+
+        self.refresh_line(ctx)
+    }
+
+    /// Start an incremental history search.
+    fn start_search(&mut self, ctx: &mut Context, forward: bool, vi: bool) -> io::Result<()> {
+        self.edit_kind = EditKind::Search;
+        self.search = Some(SearchState {
+            query: String::new(),
+            forward,
+            vi,
+            match_index: self.history_index,
+            failed: false,
+            original_line: self.buffer.chars.clone(),
+            original_pos: self.buffer.pos,
+        });
+        self.refresh_line(ctx)
+    }
+
+    /// Look for the search query in the history, starting at history index
+    /// `from` and moving towards older entries, or newer ones when
+    /// searching forward. Entries equal to `skip` are passed over.
+    fn search_history(
+        &mut self,
+        ctx: &mut Context,
+        from: usize,
+        skip: Option<&str>,
+    ) -> io::Result<()> {
+        let Some(ref mut search) = self.search else {
+            return Ok(());
+        };
+
+        let mut index = from;
+        let mut found = None;
+        while index > 0 && index <= ctx.history.entries.len() {
+            let entry = ctx.history.get(index).unwrap();
+            let pos = if search.forward {
+                entry.find(&search.query)
+            } else {
+                entry.rfind(&search.query)
+            };
+            if let Some(pos) = pos
+                && skip != Some(entry)
+            {
+                found = Some((entry, entry[..pos].chars().count()));
+                break;
+            }
+            if search.forward {
+                index -= 1;
+            } else {
+                index += 1;
+            }
+        }
+
+        match found {
+            Some((entry, pos)) => {
+                search.match_index = index;
+                search.failed = false;
+                self.buffer.set(entry);
+                self.buffer.pos = pos;
+            }
+            None => {
+                search.failed = true;
+                self.terminal.beep();
+            }
+        }
+
+        self.refresh_line(ctx)
+    }
+
+    /// Handle a key during incremental history search.
+    fn handle_search_key(&mut self, ctx: &mut Context, key: KeyEvent) -> io::Result<FeedResult> {
+        let Some(ref mut search) = self.search else {
+            return self.handle_key(ctx, key);
+        };
+        self.edit_kind = EditKind::Search;
+        let cmd = ctx.keymap.command(&[key]);
+
+        match key.code {
+            _ if matches!(
+                cmd,
+                Some(Cmd::HistorySearchBackward | Cmd::HistorySearchForward)
+            ) =>
+            {
+                // Find the next match in the given direction
+                search.forward = cmd == Some(Cmd::HistorySearchForward);
+                let from = if search.forward {
+                    search.match_index.saturating_sub(1)
+                } else {
+                    search.match_index + 1
+                };
+                if search.query.is_empty() {
+                    self.refresh_line(ctx)?;
+                } else {
+                    let current = self.buffer.as_string();
+                    self.search_history(ctx, from, Some(&current))?;
+                }
+            }
+            _ if key == KeyEvent::ctrl('g') => self.cancel_search(ctx)?,
+            _ if cmd == Some(Cmd::DeleteCharLeft) => {
+                search.query.pop();
+                if search.query.is_empty() {
+                    search.match_index = self.history_index;
+                    search.failed = false;
+                    self.buffer.chars.clone_from(&search.original_line);
+                    self.buffer.pos = search.original_pos;
+                    self.refresh_line(ctx)?;
+                } else {
+                    // Search again from where we started
+                    let from = if search.forward {
+                        self.history_index
+                    } else {
+                        self.history_index.max(1)
+                    };
+                    self.search_history(ctx, from, None)?;
+                }
+            }
+            KeyCode::Esc => self.cancel_search(ctx)?,
+            _ if search.vi && cmd == Some(Cmd::AcceptLine) => {
+                self.accept_search(ctx)?;
+                self.edit_kind = EditKind::Other;
+            }
+            KeyCode::Char(ch) if key.modifiers.is_empty() => {
+                search.query.push(ch);
+                // The current match may still match the longer query
+                let from = match search.match_index {
+                    0 if search.forward => 0,
+                    0 => 1,
+                    index => index,
+                };
+                self.search_history(ctx, from, None)?;
+            }
+            _ => {
+                // Any other key accepts the match and is processed as usual
+                self.accept_search(ctx)?;
+                self.edit_kind = EditKind::Other;
+                return self.handle_key(ctx, key);
+            }
+        }
+
+        Ok(FeedResult::Pending)
+    }
+
+    /// Leave incremental search keeping the matched line, so that history
+    /// navigation continues from it.
+    fn accept_search(&mut self, ctx: &mut Context) -> io::Result<()> {
+        if let Some(search) = self.search.take() {
+            if search.match_index > 0 && search.match_index != self.history_index {
+                if self.history_index == 0 {
+                    self.saved_line = Some(search.original_line.iter().collect());
+                }
+                self.history_index = search.match_index;
+            }
+            self.refresh_line(ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Leave incremental search restoring the line as it was before.
+    fn cancel_search(&mut self, ctx: &mut Context) -> io::Result<()> {
+        if let Some(search) = self.search.take() {
+            self.buffer.chars = search.original_line;
+            self.buffer.pos = search.original_pos;
+            self.refresh_line(ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Process the keys and pastes in `bytes`, redrawing the line once at
+    /// the end rather than after each of them. Bytes after an input that
+    /// is done with the line are kept for the next one. When the bytes end
+    /// with what could be a key by itself, like Esc, the rest of a sequence
+    /// is waited for up to the Esc timeout with `wait`, and not at all
+    /// without it.
+    fn process_bytes(
+        &mut self,
+        ctx: &mut Context,
+        bytes: &[u8],
+        wait: bool,
+    ) -> io::Result<FeedResult> {
+        self.batching = true;
+        let result = self.process_batch(ctx, bytes, wait);
+        self.batching = false;
+        self.flush_refresh(ctx)?;
+        result
+    }
+
+    fn process_batch(
+        &mut self,
+        ctx: &mut Context,
+        bytes: &[u8],
+        wait: bool,
+    ) -> io::Result<FeedResult> {
+        for (i, &c) in bytes.iter().enumerate() {
+            let input = match self.decoder.push(c) {
+                Some(input) => input,
+                None if i + 1 == bytes.len()
+                    && self.decoder.is_ambiguous()
+                    && !(wait && self.terminal.wait_input(ctx.esc_timeout)?) =>
+                {
+                    match self.decoder.flush() {
+                        Some(input) => input,
+                        None => continue,
+                    }
+                }
+                None => continue,
+            };
+            let result = self.process_input(ctx, input)?;
+            if result != FeedResult::Pending {
+                self.leftover.input.extend_from_slice(&bytes[i + 1..]);
+                return Ok(result);
+            }
+        }
+        Ok(FeedResult::Pending)
+    }
+
+    /// Process a single key or paste, recording the change it made to the
+    /// line for undo.
+    fn process_input(&mut self, ctx: &mut Context, input: Input) -> io::Result<FeedResult> {
+        let before = (self.buffer.chars.clone(), self.buffer.pos);
+        self.validation_error = None;
+        self.edit_kind = EditKind::Other;
+        self.last_kill_action = mem::replace(&mut self.kill_action, KillAction::None);
+        let result = match input {
+            Input::Key(key) => self.handle_key(ctx, key),
+            Input::Paste(text) => self.handle_paste(ctx, text),
+        };
+        self.undo.record(before, &self.buffer.chars, self.edit_kind);
+        result
+    }
+
+    /// Insert pasted text at the cursor as a single edit, after the paste
+    /// filter and with line breaks as `paste_newlines` says. A completion
+    /// or search in progress is accepted first.
+    fn handle_paste(&mut self, ctx: &mut Context, text: String) -> io::Result<FeedResult> {
+        self.completion_query = None;
+        self.pending_keys.clear();
+        self.accept_search(ctx)?;
+        self.accept_completion(ctx)?;
+
+        let mut text = match &ctx.paste_filter {
+            Some(filter) => filter.lock().unwrap()(&text),
+            None => text,
+        };
+        let mut submit = false;
+        match ctx.paste_newlines {
+            PasteNewlines::Insert => {}
+            PasteNewlines::Strip => text.retain(|c| c != '\n'),
+            PasteNewlines::Submit => {
+                if let Some(i) = text.find('\n') {
+                    self.leftover.paste = text.split_off(i + 1);
+                    text.pop();
+                    submit = true;
+                }
+            }
+        }
+
+        let pos = self.buffer.pos;
+        self.buffer.replace(pos..pos, &text);
+        self.refresh_line(ctx)?;
+
+        if submit {
+            return self.run_command(ctx, Cmd::AcceptLine);
+        }
+        Ok(FeedResult::Pending)
+    }
+
+    fn handle_key(&mut self, ctx: &mut Context, key: KeyEvent) -> io::Result<FeedResult> {
+        if let Some(candidates) = self.completion_query.take() {
+            self.answer_completion_query(ctx, candidates, key)?;
+            return Ok(FeedResult::Pending);
+        }
+
+        // Esc and the key typed after it can arrive together as Alt+key.
+        // Take them apart again when Alt+key isn't bound, and in vi insert
+        // mode, where Esc returns to normal mode, unless it inserts a line
+        // break.
+        let vi_insert = ctx.edit_mode == EditMode::Vi && self.vi.mode == ViMode::Insert;
+        if key.modifiers.contains(Modifiers::ALT)
+            && self.pending_keys.is_empty()
+            && ctx.keymap.command(&[key]) != Some(Cmd::InsertNewline)
+            && (vi_insert || ctx.keymap.get(&[key]).is_none() && !ctx.keymap.is_prefix(&[key]))
+        {
+            let result = self.handle_key(ctx, KeyCode::Esc.into())?;
+            if result != FeedResult::Pending {
+                return Ok(result);
+            }
+            let key = KeyEvent::new(key.code, key.modifiers.without(Modifiers::ALT));
+            return self.handle_key(ctx, key);
+        }
+
+        if self.search.is_some() {
+            return self.handle_search_key(ctx, key);
+        }
+
+        // Handle completion menu
+        if self.completion_state.as_ref().is_some_and(|s| s.menu)
+            && self.handle_menu_key(ctx, key)?
+        {
+            return Ok(FeedResult::Pending);
+        }
+
+        // Esc and normal mode commands are not in the keymap
+        if ctx.edit_mode == EditMode::Vi && self.pending_keys.is_empty() {
+            let vi_key = match key.code {
+                KeyCode::Char(ch) if key.modifiers.is_empty() => Some(ch),
+                KeyCode::Backspace => Some('h'),
+                _ if key == KeyEvent::ctrl('h') => Some('h'),
+                _ => None,
+            };
+            if key == KeyCode::Esc.into() {
+                self.accept_completion(ctx)?;
+                self.vi_escape(ctx)?;
+                return Ok(FeedResult::Pending);
+            }
+            if self.vi.mode == ViMode::Normal
+                && let Some(ch) = vi_key
+            {
+                self.accept_completion(ctx)?;
+                return self.handle_vi_key(ctx, ch);
+            }
+        }
+
+        // Wait for the rest of a key sequence
+        self.pending_keys.push(key);
+        if ctx.keymap.get(&self.pending_keys).is_none() && ctx.keymap.is_prefix(&self.pending_keys)
+        {
+            return Ok(FeedResult::Pending);
+        }
+        let keys = mem::take(&mut self.pending_keys);
+        let cmd = ctx.keymap.command(&keys);
+
+        if self.completion_state.is_some() && cmd != Some(Cmd::Complete) {
+            self.accept_completion(ctx)?;
+        }
+
+        if let Some(cmd) = cmd {
+            return self.run_command(ctx, cmd);
+        }
+
+        if let Some(Binding::Func(f)) = ctx.keymap.get(&keys) {
+            let mut line = self.buffer.as_string();
+            let mut pos = self.buffer.pos;
+            f.lock().unwrap()(&mut line, &mut pos);
+            self.buffer.set(&line);
+            self.buffer.pos = min(pos, self.buffer.chars.len());
+            self.refresh_line(ctx)?;
+        } else {
+            match key.code {
+                // Unbound characters insert themselves
+                KeyCode::Char(ch) if keys.len() == 1 && key.modifiers.is_empty() => {
+                    self.edit_kind = EditKind::Insert;
+                    if self.buffer.insert(ch) {
+                        self.refresh_line(ctx)?;
+                    } else {
+                        self.terminal.beep();
+                    }
+                }
+                _ if keys.len() > 1 => self.terminal.beep(),
+                _ => {}
+            }
+        }
+
+        Ok(FeedResult::Pending)
+    }
+
+    /// Run an editor command bound to a key.
+    fn run_command(&mut self, ctx: &mut Context, cmd: Cmd) -> io::Result<FeedResult> {
+        match cmd {
+            Cmd::AcceptLine => {
+                let line = self.buffer.as_string();
+                let validation = match &ctx.validator {
+                    Some(validator) => validator.lock().unwrap()(&line),
+                    None => Validation::Complete,
+                };
+                match validation {
+                    Validation::Complete => {
+                        self.clear_below(ctx)?;
+                        Ok(FeedResult::Line(line))
+                    }
+                    Validation::Incomplete => key_action!(self, ctx, self.buffer.insert('\n')),
+                    Validation::Invalid(message) => {
+                        key_action!(self, ctx, self.validation_error = Some(message))
+                    }
+                }
+            }
+            Cmd::InsertNewline => key_action!(self, ctx, self.buffer.insert('\n')),
+            Cmd::Interrupt => {
+                self.clear_below(ctx)?;
+                Ok(FeedResult::Interrupted)
+            }
+            Cmd::DeleteCharOrEof if self.buffer.chars.is_empty() => {
+                self.clear_below(ctx)?;
+                Ok(FeedResult::Eof)
+            }
+            Cmd::DeleteCharOrEof | Cmd::DeleteChar => key_action!(self, ctx, {
+                self.buffer.delete();
+            }),
+            Cmd::Complete => {
+                self.handle_completion(ctx)?;
+                Ok(FeedResult::Pending)
+            }
+            Cmd::DeleteCharLeft => key_action!(self, ctx, {
+                self.buffer.backspace();
+            }),
+            Cmd::KillWholeLine => key_action!(self, ctx, {
+                let killed = self.buffer.clear();
+                self.kill(ctx, killed, true);
+            }),
+            Cmd::KillLine => key_action!(self, ctx, {
+                let killed = self.buffer.delete_to_end();
+                self.kill(ctx, killed, false);
+            }),
+            Cmd::KillWordLeft => key_action!(self, ctx, {
+                let killed = self.buffer.delete_word(&**ctx.word_chars.lock().unwrap());
+                self.kill(ctx, killed, true);
+            }),
+            Cmd::KillWordRight => key_action!(self, ctx, {
+                let killed = self
+                    .buffer
+                    .delete_word_right(&**ctx.word_chars.lock().unwrap());
+                self.kill(ctx, killed, false);
+            }),
+            Cmd::Yank => {
+                self.yank(ctx)?;
+                Ok(FeedResult::Pending)
+            }
+            Cmd::YankPop => {
+                self.yank_pop(ctx)?;
+                Ok(FeedResult::Pending)
+            }
+            Cmd::MoveHome => key_action!(self, ctx, self.buffer.move_home()),
+            Cmd::MoveEnd => key_action!(self, ctx, {
+                if !self.accept_suggestion(ctx, false) {
+                    self.buffer.move_end();
+                }
+            }),
+            Cmd::MoveLeft => key_action!(self, ctx, {
+                self.buffer.move_left();
+            }),
+            Cmd::MoveRight => key_action!(self, ctx, {
+                if !self.accept_suggestion(ctx, false) {
+                    self.buffer.move_right();
+                }
+            }),
+            Cmd::MoveWordLeft => key_action!(self, ctx, {
+                self.buffer
+                    .move_word_left(&**ctx.word_chars.lock().unwrap());
+            }),
+            Cmd::MoveWordRight => key_action!(self, ctx, {
+                if !self.accept_suggestion(ctx, true) {
+                    self.buffer
+                        .move_word_right(&**ctx.word_chars.lock().unwrap());
+                }
+            }),
+            Cmd::HistoryPrevious | Cmd::HistoryNext => {
+                // Move between the lines of a multi-line input first
+                let moved = if cmd == Cmd::HistoryPrevious {
+                    self.buffer.move_line_up()
+                } else {
+                    self.buffer.move_line_down()
+                };
+                if moved {
+                    self.refresh_line(ctx)?;
+                } else {
+                    self.handle_history(ctx, if cmd == Cmd::HistoryPrevious { 1 } else { -1 })?;
+                }
+                Ok(FeedResult::Pending)
+            }
+            Cmd::HistorySearchBackward | Cmd::HistorySearchForward => {
+                self.start_search(ctx, cmd == Cmd::HistorySearchForward, false)?;
+                Ok(FeedResult::Pending)
+            }
+            Cmd::Undo | Cmd::Redo => {
+                self.undo(ctx, cmd == Cmd::Redo)?;
+                Ok(FeedResult::Pending)
+            }
+            Cmd::ClearScreen => {
+                self.terminal.clear_screen()?;
+                self.old_rows = 0;
+                self.cursor_row_offset = 0;
+                self.menu_rows = 0;
+                self.refresh_line(ctx)?;
+                Ok(FeedResult::Pending)
+            }
+            Cmd::TransposeWords => {
+                if self
+                    .buffer
+                    .transpose_words(&**ctx.word_chars.lock().unwrap())
+                {
+                    self.refresh_line(ctx)?;
+                } else {
+                    self.terminal.beep();
+                }
+                Ok(FeedResult::Pending)
+            }
+            Cmd::UpcaseWord | Cmd::DowncaseWord | Cmd::CapitalizeWord => key_action!(self, ctx, {
+                let case = match cmd {
+                    Cmd::UpcaseWord => WordCase::Upper,
+                    Cmd::DowncaseWord => WordCase::Lower,
+                    _ => WordCase::Capitalize,
+                };
+                self.buffer
+                    .change_word_case(&**ctx.word_chars.lock().unwrap(), case);
+            }),
+            Cmd::TransposeChars => {
+                if self.buffer.transpose_chars() {
+                    self.refresh_line(ctx)?;
+                }
+                Ok(FeedResult::Pending)
+            }
+        }
+    }
+}
+
+/// Convert `c` to upper or lower case, leaving it alone if it would take
+/// more than one character, like `ß` in upper case.
+fn change_case(c: char, upper: bool) -> char {
+    let mut changed = String::new();
+    if upper {
+        changed.extend(c.to_uppercase());
+    } else {
+        changed.extend(c.to_lowercase());
+    }
+    let mut chars = changed.chars();
+    match (chars.next(), chars.next()) {
+        (Some(changed), None) => changed,
+        _ => c,
+    }
+}
+
+/// The offsets of the grapheme cluster boundaries in `chars`, from 0 to
+/// its length.
+fn grapheme_boundaries(chars: &[char]) -> Vec<usize> {
+    let text: String = chars.iter().collect();
+    let mut boundaries = vec![0];
+    let mut offset = 0;
+    for g in text.graphemes(true) {
+        offset += g.chars().count();
+        boundaries.push(offset);
+    }
+    boundaries
+}
+
+/// The start of the grapheme cluster before `pos` in `chars`.
+fn prev_grapheme(chars: &[char], pos: usize) -> usize {
+    grapheme_boundaries(chars)
+        .into_iter()
+        .rev()
+        .find(|&b| b < pos)
+        .unwrap_or(0)
+}
+
+/// The end of the grapheme cluster at `pos` in `chars`.
+fn next_grapheme(chars: &[char], pos: usize) -> usize {
+    grapheme_boundaries(chars)
+        .into_iter()
+        .find(|&b| b > pos)
+        .unwrap_or(chars.len())
+}
+
+/// The terminal columns taken by each of `chars`. A grapheme cluster's
+/// width counts on its first character, so combining marks and the rest
+/// of an emoji sequence take none.
+fn char_widths(chars: &[char]) -> Vec<usize> {
+    let text: String = chars.iter().collect();
+    let mut widths = Vec::with_capacity(chars.len());
+    for g in text.graphemes(true) {
+        // Control characters are shown like ^J
+        if g.starts_with(|c: char| c.is_ascii_control()) {
+            widths.push(2);
+        } else {
+            widths.push(g.width());
+        }
+        widths.extend(std::iter::repeat_n(0, g.chars().count() - 1));
+    }
+    widths
+}
+
+/// The longest prefix of `s` that fits in `width` terminal columns,
+/// without splitting a grapheme cluster.
+fn truncate_to_width(s: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (i, g) in s.grapheme_indices(true) {
+        used += g.width();
+        if used > width {
+            return &s[..i];
+        }
+    }
+    s
+}
+
+/// Return `s` up to its first line break.
+fn first_line(s: &str) -> &str {
+    s.split('\n').next().unwrap_or_default()
+}
+
+/// Return the longest prefix of `texts[0]` shared by all of `texts`, never
+/// splitting a grapheme cluster.
+fn common_prefix<'a>(texts: &[&'a str], ignore_case: bool) -> &'a str {
+    let Some((first, rest)) = texts.split_first() else {
+        return "";
+    };
+
+    let same = |a: &str, b: &str| {
+        if ignore_case {
+            a.to_lowercase() == b.to_lowercase()
+        } else {
+            a == b
+        }
+    };
+
+    let mut others: Vec<_> = rest.iter().map(|t| t.graphemes(true)).collect();
+    let mut len = 0;
+    for g in first.graphemes(true) {
+        if !others
+            .iter_mut()
+            .all(|o| o.next().is_some_and(|h| same(g, h)))
+        {
+            break;
+        }
+        len += g.len();
+    }
+
+    &first[..len]
+}
+
+/// Lay out `items` in columns on a terminal `cols` wide. Returns the number
+/// of items per row and the width of each column.
+fn column_layout(items: &[&str], cols: usize) -> (usize, usize) {
+    let longest = items.iter().map(|s| s.width()).max().unwrap_or(0);
+    let width = min(longest + 2, cols.max(1));
+    (cols / width, width)
+}
+
+/// Return true if the terminal name is in the list of terminals we know
+/// are not able to understand basic escape sequences.
+fn is_unsupported_term() -> bool {
+    let unsupported = ["dumb", "cons25", "emacs"];
+    if let Ok(term) = env::var("TERM") {
+        unsupported.iter().any(|&t| term.eq_ignore_ascii_case(t))
+    } else {
+        false
+    }
+}
+
+// Public API
+
+/// A line editor instance.
+///
+/// Each instance owns its own settings, history and callbacks, so several
+/// prompts in one program (e.g. a REPL and a nested password prompt, or two
+/// sessions on different file descriptors) don't interfere with each other.
+/// The `linenoise_*` free functions operate on a process-wide default
+/// instance.
+pub struct Linenoise {
+    ctx: Context,
+    session: Option<Session>,
+    /// Input read past the end of the last line, for the next one.
+    leftover: Leftover,
+    /// Why the last `readline()` returned `None`.
+    last_error: Option<Error>,
+}
+
+/// An edit session started with [`Linenoise::edit_start`].
+struct Session {
+    editor: Editor,
+    active: bool,
+    /// Bracketed paste mode was turned on, to turn off at the end.
+    bracketed_paste: bool,
+}
+
+impl Drop for Session {
+    /// Restore the terminal.
+    fn drop(&mut self) {
+        if self.bracketed_paste {
+            let _ = self.editor.terminal.write("\x1b[?2004l");
+        }
+        let _ = self.editor.terminal.backend.disable_raw_mode();
+    }
+}
+
+impl Session {
+    /// Start editing a line on `terminal`, switching it to raw mode and
+    /// showing the prompt. Input left from the previous line is processed
+    /// first.
+    fn start(
+        ctx: &mut Context,
+        terminal: Box<dyn TerminalBackend>,
+        prompt: &str,
+        leftover: Leftover,
+    ) -> io::Result<Self> {
+        let mut terminal = Terminal::new(terminal);
+        terminal.backend.enable_raw_mode()?;
+        let mut editor = Editor::new(terminal, prompt);
+        editor.leftover = leftover;
+        let mut session = Session {
+            editor,
+            active: true,
+            bracketed_paste: ctx.bracketed_paste,
+        };
+        if session.bracketed_paste {
+            session.editor.terminal.write("\x1b[?2004h")?;
+        }
+
+        // Display initial prompt
+        session.editor.refresh_line(ctx)?;
+        Ok(session)
+    }
+
+    /// Process the input there is, reading it without the context: see
+    /// [`Linenoise::edit_feed`].
+    fn feed(&mut self, ctx: &mut impl ContextAccess) -> Result<FeedResult, Error> {
+        if !self.active {
+            return Ok(FeedResult::Eof);
+        }
+
+        // Go on with the rest of a paste submitted line by line, or with
+        // input left from the previous line
+        let editor = &mut self.editor;
+        let queued = ctx.with(|ctx| {
+            if !editor.leftover.paste.is_empty() {
+                let text = mem::take(&mut editor.leftover.paste);
+                editor.process_input(ctx, Input::Paste(text)).map(Some)
+            } else if !editor.leftover.input.is_empty() {
+                let bytes = mem::take(&mut editor.leftover.input);
+                editor.process_bytes(ctx, &bytes, true).map(Some)
+            } else {
+                Ok(None)
+            }
+        })?;
+        if let Some(result) = queued {
+            return Ok(self.finish(result));
+        }
+
+        // Or read what there is, going on with the rest of a key sequence
+        // if it is there
+        let mut buf = [0u8; 4096];
+        let result = loop {
+            let n = match self.editor.terminal.read(&mut buf) {
+                Ok(0) => {
+                    self.active = false;
+                    return Err(Error::Closed);
+                }
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(FeedResult::Pending);
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let editor = &mut self.editor;
+            let result = ctx.with(|ctx| editor.process_bytes(ctx, &buf[..n], true))?;
+            if result != FeedResult::Pending
+                || !self.editor.decoder.is_pending()
+                || !self.editor.terminal.wait_input(Duration::ZERO)?
+            {
+                break result;
+            }
+        };
+        Ok(self.finish(result))
+    }
+
+    /// Process `bytes`: see [`Linenoise::feed_bytes`].
+    fn feed_bytes(&mut self, ctx: &mut Context, bytes: &[u8]) -> Result<FeedResult, Error> {
+        if !self.active {
+            return Ok(FeedResult::Eof);
+        }
+
+        let leftover = &mut self.editor.leftover;
+        leftover.input.extend_from_slice(bytes);
+        let result = if !leftover.paste.is_empty() {
+            let text = mem::take(&mut leftover.paste);
+            self.editor.process_input(ctx, Input::Paste(text))?
+        } else {
+            let bytes = mem::take(&mut leftover.input);
+            self.editor.process_bytes(ctx, &bytes, false)?
+        };
+        Ok(self.finish(result))
+    }
+
+    /// Wrap up the session if `result` is done with the line.
+    fn finish(&mut self, result: FeedResult) -> FeedResult {
+        match result {
+            FeedResult::Pending => {}
+            FeedResult::Line(_) => {
+                // Move to new line before returning
+                let _ = self.editor.terminal.write("\r\n");
+                self.active = false;
+            }
+            FeedResult::Eof | FeedResult::Interrupted => self.active = false,
+        }
+        result
+    }
+
+    /// Take the input read past the end of the line, for the next one.
+    fn take_leftover(&mut self) -> Leftover {
+        mem::take(&mut self.editor.leftover)
+    }
+
+    fn hide(&mut self) -> io::Result<()> {
+        // Move to beginning of line and clear it, and the rows below
+        self.editor.terminal.write("\r\x1b[0J")
+    }
+
+    fn show(&mut self, ctx: &mut Context) -> io::Result<()> {
+        // Instead of restoring cursor position which might be stale,
+        // just move to beginning of line and refresh
+        self.editor.terminal.write("\r")?;
+        self.editor.refresh_line(ctx)
+    }
+
+    fn fd(&self) -> RawFd {
+        self.editor.terminal.backend.input_fd().unwrap_or(-1)
+    }
+}
+
+/// Read a line from stdin, with editing if it is a terminal that supports
+/// it. Input read past the end of the line is kept in `leftover` for the
+/// next one.
+fn read_line(
+    ctx: &mut impl ContextAccess,
+    leftover: &mut Leftover,
+    prompt: &str,
+) -> Result<String, Error> {
+    let terminal = FdTerminal::new(libc::STDIN_FILENO, libc::STDOUT_FILENO);
+
+    if !terminal.is_tty() {
+        return linenoise_no_tty();
+    }
+
+    if is_unsupported_term() {
+        return linenoise_unsupported_term(prompt);
+    }
+
+    // Read until we get a result. Dropping the session restores the
+    // terminal.
+    let mut session =
+        ctx.with(|ctx| Session::start(ctx, Box::new(terminal), prompt, mem::take(leftover)))?;
+    let result = loop {
+        match session.feed(ctx) {
+            Ok(FeedResult::Pending) => continue,
+            Ok(FeedResult::Line(line)) => break Ok(line),
+            Ok(FeedResult::Eof) => break Err(Error::Eof),
+            Ok(FeedResult::Interrupted) => break Err(Error::Interrupted),
+            Err(err) => break Err(err),
+        }
+    };
+    *leftover = session.take_leftover();
+    result
+}
+
+/// The terminal on the file descriptors given to `edit_start()`, `-1`
+/// selecting stdin or stdout.
+fn open_terminal(stdin_fd: RawFd, stdout_fd: RawFd) -> io::Result<FdTerminal> {
+    let ifd = if stdin_fd == -1 {
+        libc::STDIN_FILENO
+    } else {
+        stdin_fd
+    };
+
+    let ofd = if stdout_fd == -1 {
+        libc::STDOUT_FILENO
+    } else {
+        stdout_fd
+    };
+
+    let terminal = FdTerminal::new(ifd, ofd);
+
+    if !terminal.is_tty() || is_unsupported_term() {
+        return Err(io::Error::other("Not supported"));
+    }
+    Ok(terminal)
+}
+
+impl Default for Linenoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Linenoise {
+    /// Create a new line editor with default settings and empty history.
+    pub fn new() -> Self {
+        Linenoise {
+            ctx: Context::new(),
+            session: None,
+            leftover: Leftover::default(),
+            last_error: None,
+        }
+    }
+
+    /// Read a line from the terminal, with editing. This checks if the
+    /// terminal has basic capabilities, just checking for a blacklist of
+    /// stupid terminals, and later either calls the line editing function
+    /// or uses dummy `fgets()` so that you will be able to type something
+    /// even in the most desperate of conditions.
+    ///
+    /// On `None`, [`take_error`](Self::take_error) tells why.
+    pub fn readline(&mut self, prompt: &str) -> Option<String> {
+        let result = self.try_readline(prompt);
+        self.keep_error(result)
+    }
+
+    /// The line read, keeping the reason there is none for
+    /// [`take_error`](Self::take_error).
+    fn keep_error(&mut self, result: Result<String, Error>) -> Option<String> {
+        match result {
+            Ok(line) => {
+                self.last_error = None;
+                Some(line)
+            }
+            Err(err) => {
+                self.last_error = Some(err);
+                None
+            }
+        }
+    }
+
+    /// Read a line like [`readline`](Self::readline), returning why there
+    /// is none as an error.
+    ///
+    /// ```no_run
+    /// use linenoise_rs::{Error, Linenoise};
+    ///
+    /// let mut ln = Linenoise::new();
+    /// loop {
+    ///     match ln.try_readline("> ") {
+    ///         Ok(line) => println!("{line}"),
+    ///         Err(Error::Interrupted) => continue,
+    ///         Err(Error::Eof | Error::Closed) => break,
+    ///         Err(err) => panic!("{err}"),
+    ///     }
+    /// }
+    /// ```
+    pub fn try_readline(&mut self, prompt: &str) -> Result<String, Error> {
+        read_line(&mut self.ctx, &mut self.leftover, prompt)
+    }
+
+    /// Take the reason the last [`readline`](Self::readline) returned
+    /// `None`, if it did.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.last_error.take()
+    }
+
+    /// Toggle multi line mode.
+    pub fn set_multi_line(&mut self, ml: bool) {
+        self.ctx.multi_line = ml;
+    }
+
+    /// Select Emacs or vi key bindings.
+    pub fn set_edit_mode(&mut self, mode: EditMode) {
+        self.ctx.edit_mode = mode;
+    }
+
+    /// Set which characters make up words, for the word movement and
+    /// editing commands. By default words are separated by whitespace;
+    /// `|c| c.is_alphanumeric() || c == '_'` also stops at `/`, `-` and
+    /// `.`, which suits paths.
+    pub fn set_word_chars<F>(&mut self, f: F)
+    where
+        F: Fn(char) -> bool + Send + 'static,
+    {
+        self.ctx.word_chars = Arc::new(Mutex::new(Box::new(f)));
+    }
+
+    /// Register a validator, called with the input when Enter is pressed.
+    /// Unless it returns [`Validation::Complete`] the input isn't
+    /// submitted: an incomplete one gets a line break and editing goes on
+    /// on the next line, and an invalid one stays as it is, with the error
+    /// shown below it. Alt-Enter always inserts a line break.
+    pub fn set_validator<F>(&mut self, cb: F)
+    where
+        F: FnMut(&str) -> Validation + Send + 'static,
+    {
+        self.ctx.validator = Some(Arc::new(Mutex::new(Box::new(cb))));
+    }
+
+    /// Set the prompt of the lines after the first of a multi-line input,
+    /// `"... "` by default.
+    pub fn set_continuation_prompt(&mut self, prompt: &str) {
+        self.ctx.continuation_prompt = prompt.to_string();
+    }
+
+    /// Turn bracketed paste mode on or off (it is on by default). In it,
+    /// the terminal marks pasted text, which is then inserted as a whole,
+    /// as a single undo step, rather than typed, so that its tabs and line
+    /// breaks don't trigger completion or submit the line.
+    pub fn set_bracketed_paste(&mut self, enable: bool) {
+        self.ctx.bracketed_paste = enable;
+    }
 
-        // Add hints if appropriate
-        if content_rows == 1 && !phantom_line && self.completion_state.is_none() {
-            if let Some(ref cb) = state.hints_callback {
-                if let Some((hint, color, bold)) = cb(&self.buffer.as_string()) {
-                    let last_line_len = content_len % cols;
-                    let space = if last_line_len == 0 {
-                        0
-                    } else {
-                        cols - last_line_len
-                    };
+    /// Choose what is done with the line breaks in pasted text.
+    pub fn set_paste_newlines(&mut self, newlines: PasteNewlines) {
+        self.ctx.paste_newlines = newlines;
+    }
 
-                    if space > 0 {
-                        let hint_str: String = hint.chars().take(space).collect();
-                        if !hint_str.is_empty() {
-                            if bold {
-                                output.push_str("\x1b[1m");
-                            }
-                            if color >= 0 {
-                                output.push_str(&format!("\x1b[{color}m"));
-                            }
-                            output.push_str(&hint_str);
-                            output.push_str("\x1b[0m");
-                        }
-                    }
-                }
-            }
-        }
+    /// Register a callback rewriting pasted text before it is inserted,
+    /// e.g. to expand tabs or drop a trailing line break.
+    pub fn set_paste_filter<F>(&mut self, f: F)
+    where
+        F: FnMut(&str) -> String + Send + 'static,
+    {
+        self.ctx.paste_filter = Some(Arc::new(Mutex::new(Box::new(f))));
+    }
 
-        // Add phantom line if needed
-        if phantom_line {
-            output.push_str("\r\n");
-        }
+    /// Set how long to wait for the rest of an escape sequence after an
+    /// Esc before taking it as a key of its own. Longer timeouts suit slow
+    /// links, shorter ones make a lone Esc, as used in vi mode, snappier.
+    /// Default is 100ms.
+    pub fn set_esc_timeout(&mut self, timeout: Duration) {
+        self.ctx.esc_timeout = timeout;
+    }
 
-        // Now position cursor
-        // We're currently at end of content
-        let current_row = total_rows - 1;
+    /// Toggle mask mode. When it is enabled, instead of the input that
+    /// the user is typing, the terminal will just display a corresponding
+    /// number of asterisks, like "***". This is useful for passwords and
+    /// other secrets that should not be displayed.
+    pub fn set_mask_mode(&mut self, enable: bool) {
+        self.ctx.mask_mode = enable;
+    }
 
-        // Move to cursor row
-        if cursor_row < current_row {
-            output.push_str(&format!("\x1b[{}A", current_row - cursor_row));
-        } else if cursor_row > current_row {
-            output.push_str(&format!("\x1b[{}B", cursor_row - current_row));
-        }
+    /// Register a callback function to be called for tab-completion.
+    /// Closures may capture application state. The callback receives the
+    /// whole line and each completion replaces the whole line; see
+    /// [`Linenoise::set_completer`] for completing just a part of it.
+    pub fn set_completion_callback<F>(&mut self, mut cb: F)
+    where
+        F: FnMut(&str, &mut Vec<String>) + Send + 'static,
+    {
+        self.set_completer(move |line, _pos, candidates| {
+            let mut completions = Vec::new();
+            cb(line, &mut completions);
+            let len = line.chars().count();
+            candidates.extend(completions.into_iter().map(|c| Candidate::new(0..len, c)));
+        });
+    }
 
-        // Move to cursor column
-        output.push_str(&format!("\r\x1b[{}C", cursor_col));
+    /// Register a context-aware completion callback. It receives the line
+    /// and the cursor position (in characters) and pushes [`Candidate`]s,
+    /// each one replacing only its own range of the line, e.g. the word
+    /// under the cursor.
+    pub fn set_completer<F>(&mut self, cb: F)
+    where
+        F: FnMut(&str, usize, &mut Vec<Candidate>) + Send + 'static,
+    {
+        self.ctx.completer = Some(Arc::new(Mutex::new(Box::new(cb))));
+    }
 
-        // Update state
-        self.old_rows = total_rows;
-        self.cursor_row_offset = cursor_row;
+    /// Select how Tab presents completion candidates.
+    pub fn set_completion_mode(&mut self, mode: CompletionMode) {
+        self.ctx.completion_mode = mode;
+    }
 
-        self.terminal.write(&output)
+    /// Make the first Tab insert the longest common prefix of all
+    /// candidates. Only the following Tabs cycle, list or show the menu,
+    /// according to the [`CompletionMode`].
+    pub fn set_completion_prefix(&mut self, enable: bool) {
+        self.ctx.completion_prefix = enable;
     }
 
-    fn handle_completion(&mut self) -> io::Result<bool> {
-        // Get the completion callback
-        let callback = {
-            let state = G.lock().unwrap();
-            state.completion_callback
-        };
+    /// Ignore case when computing the common prefix of the candidates.
+    pub fn set_completion_ignore_case(&mut self, enable: bool) {
+        self.ctx.completion_ignore_case = enable;
+    }
 
-        let Some(cb) = callback else {
-            return Ok(false);
-        };
+    /// In [`CompletionMode::List`], ask "Display all N possibilities?"
+    /// before listing more than `items` candidates.
+    pub fn set_completion_query_items(&mut self, items: usize) {
+        self.ctx.completion_query_items = items;
+    }
 
-        // Determine which line to use for completion
-        let line_for_completion = if let Some(ref comp_state) = self.completion_state {
-            // Use the original line saved when completion started
-            comp_state.original_line.clone()
-        } else {
-            // First tab - use current buffer
-            self.buffer.as_string()
-        };
+    /// Registers a hints function to be called to show hints to the user
+    /// at the right of the prompt. Closures may capture application state.
+    /// The hint is a text, an SGR color code from 30 to 37 or 90 to 97 (or
+    /// -1 for none) and whether it's bold; see [`Linenoise::set_hinter`]
+    /// for other styles.
+    pub fn set_hints_callback<F>(&mut self, mut cb: F)
+    where
+        F: FnMut(&str) -> Option<(String, i32, bool)> + Send + 'static,
+    {
+        self.set_hinter(move |line| {
+            let (text, color, bold) = cb(line)?;
+            let mut style = Style::new();
+            style.fg = Color::from_sgr(color);
+            style.bold = bold;
+            Some(Hint::new(text).style(style))
+        });
+    }
 
-        // Get completions
-        let mut completions = Vec::new();
-        cb(&line_for_completion, &mut completions);
+    /// Register a hints function returning a [`Hint`], with any [`Style`],
+    /// shown at the right of the line or on the row below it.
+    pub fn set_hinter<F>(&mut self, cb: F)
+    where
+        F: FnMut(&str) -> Option<Hint> + Send + 'static,
+    {
+        self.ctx.hinter = Some(Arc::new(Mutex::new(Box::new(cb))));
+    }
 
-        if completions.is_empty() {
-            self.terminal.beep();
-            self.completion_state = None;
-            return Ok(false);
-        }
+    /// Register a highlighter, called with the line and the cursor position
+    /// (in characters) on every refresh. It returns the [`Span`]s of the
+    /// line to display styled, e.g. keywords or unbalanced quotes. Lines
+    /// are not highlighted in mask mode.
+    pub fn set_highlighter<F>(&mut self, cb: F)
+    where
+        F: FnMut(&str, usize) -> Vec<Span> + Send + 'static,
+    {
+        self.ctx.highlighter = Some(Arc::new(Mutex::new(Box::new(cb))));
+    }
 
-        // Update completion state
-        if let Some(ref mut comp_state) = self.completion_state {
-            // Already in completion mode - cycle to next
-            comp_state.current_index = (comp_state.current_index + 1) % completions.len();
+    /// Make Up/Down (and Ctrl-P/Ctrl-N) only step through history entries
+    /// starting with the text typed before navigation began, keeping the
+    /// cursor right after it, like zsh's `history-beginning-search-backward`.
+    pub fn set_history_prefix_search(&mut self, enable: bool) {
+        self.ctx.history_prefix_search = enable;
+    }
 
-            if let Some(completion) = completions.get(comp_state.current_index) {
-                self.buffer.set(completion);
-                self.refresh_line()?;
-            }
-        } else {
-            // First tab - start completion mode
-            self.completion_state = Some(CompletionState {
-                original_line: line_for_completion,
-                current_index: 0,
-            });
+    /// Show the rest of the most recent history entry extending the line,
+    /// dimmed, after the cursor (fish style autosuggestions). Right, End,
+    /// Ctrl-F or Ctrl-E at the end of the line accept it, Alt-F accepts
+    /// its next word. Composes with the hints callback, which is asked
+    /// about the suggested line.
+    pub fn set_autosuggest(&mut self, enable: bool) {
+        self.ctx.autosuggest = enable;
+    }
 
-            // Show first completion
-            if let Some(first) = completions.first() {
-                self.buffer.set(first);
-                self.refresh_line()?;
-            }
-        }
+    /// Bind a key, or a sequence of keys like Ctrl-X Ctrl-U, to an editor
+    /// command, replacing its previous binding. In vi mode this applies to
+    /// insert mode and to keys normal mode doesn't handle.
+    pub fn bind_key(&mut self, keys: &[KeyEvent], cmd: Cmd) {
+        self.ctx.keymap.bind(keys, Binding::Cmd(cmd));
+    }
 
-        Ok(true)
+    /// Bind a key, or a sequence of keys, to a closure. It receives the
+    /// line and the cursor position, in characters, and may change both.
+    pub fn bind_key_fn<F>(&mut self, keys: &[KeyEvent], f: F)
+    where
+        F: FnMut(&mut String, &mut usize) + Send + 'static,
+    {
+        self.ctx
+            .keymap
+            .bind(keys, Binding::Func(Arc::new(Mutex::new(Box::new(f)))));
     }
 
-    fn accept_completion(&mut self) {
-        self.completion_state = None;
+    /// Remove the binding of a key or a sequence of keys. Unbound
+    /// characters insert themselves, other unbound keys do nothing.
+    pub fn unbind_key(&mut self, keys: &[KeyEvent]) {
+        self.ctx.keymap.unbind(keys);
     }
 
-    fn handle_history(&mut self, direction: isize) -> io::Result<()> {
-        let state = G.lock().unwrap();
-        let history_len = state.history.entries.len();
+    /// Add a new entry to the history.
+    pub fn history_add(&mut self, line: &str) -> bool {
+        Arc::make_mut(&mut self.ctx.history).add(line)
+    }
 
-        if history_len == 0 {
-            return Ok(());
+    /// Set the maximum length for the history. This function can be called
+    /// even if there is already some history, the function will make sure
+    /// to retain just the latest `len` elements if the new history length
+    /// value is smaller than the amount of items already inside the history.
+    pub fn history_set_max_len(&mut self, len: usize) -> bool {
+        if len < 1 {
+            return false;
         }
-
-        // Save current line on first history access
-        if self.history_index == 0 && self.saved_line.is_none() {
-            self.saved_line = Some(self.buffer.as_string());
+        let history = Arc::make_mut(&mut self.ctx.history);
+        history.max_len = len;
+        while history.entries.len() > len {
+            history.entries.pop_front();
         }
+        true
+    }
 
-        // Update history index
-        if direction > 0 {
-            if self.history_index < history_len {
-                self.history_index += 1;
+    /// Save the history to the specified file, one entry per line. If an
+    /// entry has line breaks or backslashes, the file starts with a header
+    /// line and they are saved as `\n` and `\\`. Other files are saved as
+    /// before, for older versions to load.
+    pub fn history_save(&self, filename: &str) -> io::Result<()> {
+        let entries = &self.ctx.history.entries;
+        let escaped = entries.iter().any(|entry| entry.contains(['\n', '\\']))
+            || entries
+                .front()
+                .is_some_and(|entry| entry == LINENOISE_HISTORY_ESCAPED);
+
+        let mut file = File::create(filename)?;
+        if escaped {
+            writeln!(file, "{LINENOISE_HISTORY_ESCAPED}")?;
+        }
+        for entry in entries {
+            if escaped {
+                writeln!(file, "{}", entry.replace('\\', "\\\\").replace('\n', "\\n"))?;
+            } else {
+                writeln!(file, "{entry}")?;
             }
-        } else if self.history_index > 0 {
-            self.history_index -= 1;
         }
+        Ok(())
+    }
 
-        // Load history entry or restore saved line
-        if self.history_index == 0 {
-            if let Some(saved) = &self.saved_line {
-                self.buffer.set(saved);
+    /// Load the history from the specified file. If the file does not exist
+    /// then no operation is performed.
+    ///
+    /// If file exists then it returns `Ok()` on success and an error on fail.
+    pub fn history_load(&mut self, filename: &str) -> io::Result<()> {
+        let file = match File::open(filename) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let reader = BufReader::new(file);
+        let history = Arc::make_mut(&mut self.ctx.history);
+
+        let mut escaped = false;
+        #[allow(clippy::manual_flatten)]
+        for (i, line) in reader.lines().enumerate() {
+            if let Ok(line) = line {
+                if i == 0 && line == LINENOISE_HISTORY_ESCAPED {
+                    escaped = true;
+                    continue;
+                }
+                let trimmed = line.trim_end();
+                if trimmed.is_empty() {
+                    continue;
+                }
+                if escaped {
+                    history.add(&unescape_history_line(trimmed));
+                } else {
+                    history.add(trimmed);
+                }
             }
-        } else if let Some(entry) = state.history.get(self.history_index) {
-            self.buffer.set(entry);
         }
 
-        drop(state);
-        self.refresh_line()
+        Ok(())
     }
 
-    fn handle_escape_sequence(&mut self) -> io::Result<()> {
-        let seq = [
-            self.terminal.read_byte_nonblocking()?,
-            self.terminal.read_byte_nonblocking()?,
-        ];
+    /// This function is part of the multiplexed API, that is used in order
+    /// to implement the blocking variant of the API but can also be called by
+    /// the user directly in an event-driven program. Passing `-1` as a file
+    /// descriptor selects stdin/stdout.
+    pub fn edit_start(
+        &mut self,
+        stdin_fd: RawFd,
+        stdout_fd: RawFd,
+        prompt: &str,
+    ) -> io::Result<()> {
+        let terminal = open_terminal(stdin_fd, stdout_fd)?;
+        self.edit_start_with(terminal, prompt)
+    }
 
-        let action: Option<fn(&mut Self) -> io::Result<()>> = match seq {
-            [Some(b'['), Some(b'A')] => Some(|s| s.handle_history(1)),
-            [Some(b'['), Some(b'B')] => Some(|s| s.handle_history(-1)),
-            [Some(b'['), Some(b'C')] => Some(|s| {
-                s.buffer.move_right();
-                Ok(())
-            }),
-            [Some(b'['), Some(b'D')] => Some(|s| {
-                s.buffer.move_left();
-                Ok(())
-            }),
-            [Some(b'['), Some(b'H')] | [Some(b'O'), Some(b'H')] => Some(|s| {
-                s.buffer.move_home();
-                Ok(())
-            }),
-            [Some(b'['), Some(b'F')] | [Some(b'O'), Some(b'F')] => Some(|s| {
-                s.buffer.move_end();
-                Ok(())
-            }),
-            _ => None,
-        };
+    /// Part of the multiplexed API: start editing a line like
+    /// [`edit_start`](Self::edit_start), on any terminal backend.
+    pub fn edit_start_with<T>(&mut self, terminal: T, prompt: &str) -> io::Result<()>
+    where
+        T: TerminalBackend + 'static,
+    {
+        // Restore the terminal of a previous session before starting anew
+        self.edit_stop()?;
+        let leftover = mem::take(&mut self.leftover);
+        let session = Session::start(&mut self.ctx, Box::new(terminal), prompt, leftover)?;
+        self.session = Some(session);
+        Ok(())
+    }
 
-        if let Some(f) = action {
-            f(self)?;
-            self.refresh_line()?;
+    /// Part of the multiplexed API. Call this function each time there is
+    /// some data to read from the input file descriptor. In case of blocking
+    /// operations this function can just be called in a loop, and block.
+    /// All the input there is gets read and processed at once, redrawing
+    /// the line once.
+    ///
+    /// Returns [`FeedResult::Pending`] until the line is done with. With a
+    /// non-blocking file descriptor that has no data left this is not an
+    /// error either. The input ending is [`Error::Closed`]. Input that came
+    /// after the end of the line is processed by the next call, without
+    /// reading.
+    pub fn edit_feed(&mut self) -> Result<FeedResult, Error> {
+        match self.session {
+            Some(ref mut session) => session.feed(&mut self.ctx),
+            None => Ok(FeedResult::Eof),
         }
+    }
 
-        // Special case for delete
-        if matches!(seq, [Some(b'['), Some(b'3')])
-            && matches!(self.terminal.read_byte_nonblocking()?, Some(b'~'))
-            && self.buffer.delete()
-        {
-            self.refresh_line()?;
+    /// Part of the multiplexed API, for programs reading the input
+    /// themselves: process `bytes` as [`edit_feed`](Self::edit_feed) does
+    /// the input it reads. An Esc at the end of `bytes` is taken as a key
+    /// of its own. Input after the end of the line is kept for the next
+    /// one, processed by the next call even with no bytes.
+    pub fn feed_bytes(&mut self, bytes: &[u8]) -> Result<FeedResult, Error> {
+        match self.session {
+            Some(ref mut session) => session.feed_bytes(&mut self.ctx, bytes),
+            None => Ok(FeedResult::Eof),
         }
+    }
 
+    /// Part of the multiplexed API. At this point the user input is in the
+    /// buffer, and we can restore the terminal in normal mode.
+    pub fn edit_stop(&mut self) -> io::Result<()> {
+        // Dropping the session restores the terminal, keeping the input
+        // after its line for the next one
+        if let Some(mut session) = self.session.take() {
+            self.leftover = session.take_leftover();
+        }
         Ok(())
     }
 
-    /// Process a single input character/byte
-    fn process_key(&mut self, c: u8) -> io::Result<Option<String>> {
-        // Handle completion state
-        if self.completion_state.is_some() && c != Key::Tab as u8 {
-            self.accept_completion();
+    /// Hide the current line, when using the multiplexed API.
+    pub fn hide(&mut self) -> io::Result<()> {
+        match self.session {
+            Some(ref mut session) => session.hide(),
+            None => Ok(()),
         }
+    }
 
-        match c {
-            c if c == Key::Enter as u8 => Ok(Some(self.buffer.as_string())),
-            c if c == Key::CtrlC as u8 => Err(io::Error::new(io::ErrorKind::Interrupted, "")),
-            c if c == Key::CtrlD as u8 => {
-                if self.buffer.chars.is_empty() {
-                    Ok(None)
-                } else {
-                    if self.buffer.delete() {
-                        self.refresh_line()?;
-                    }
-                    Err(io::Error::new(
-                        io::ErrorKind::WouldBlock,
-                        "More input needed",
-                    ))
-                }
-            }
-            c if c == Key::Tab as u8 => {
-                self.handle_completion()?;
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            c if c == Key::Backspace as u8 || c == Key::CtrlH as u8 => key_action!(self, {
-                self.buffer.backspace();
-            }),
-            c if c == Key::CtrlU as u8 => key_action!(self, self.buffer.clear()),
-            c if c == Key::CtrlK as u8 => key_action!(self, self.buffer.delete_to_end()),
-            c if c == Key::CtrlW as u8 => key_action!(self, self.buffer.delete_word()),
-            c if c == Key::CtrlA as u8 => key_action!(self, self.buffer.move_home()),
-            c if c == Key::CtrlE as u8 => key_action!(self, self.buffer.move_end()),
-            c if c == Key::CtrlB as u8 => key_action!(self, {
-                self.buffer.move_left();
-            }),
-            c if c == Key::CtrlF as u8 => key_action!(self, {
-                self.buffer.move_right();
-            }),
-            c if c == Key::CtrlP as u8 => {
-                self.handle_history(1)?;
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            c if c == Key::CtrlN as u8 => {
-                self.handle_history(-1)?;
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            c if c == Key::CtrlL as u8 => {
-                self.terminal.clear_screen()?;
-                self.old_rows = 0;
-                self.cursor_row_offset = 0;
-                self.refresh_line()?;
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            c if c == Key::CtrlT as u8 => {
-                // Transpose chars
-                if self.buffer.pos > 0 && self.buffer.chars.len() > 1 {
-                    if self.buffer.pos == self.buffer.chars.len() {
-                        self.buffer
-                            .chars
-                            .swap(self.buffer.pos - 2, self.buffer.pos - 1);
-                    } else {
-                        self.buffer.chars.swap(self.buffer.pos - 1, self.buffer.pos);
-                        self.buffer.pos += 1;
-                    }
-                    self.refresh_line()?;
-                }
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            c if c == Key::Esc as u8 => {
-                self.handle_escape_sequence()?;
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            c if c >= 32 && c < 127 => {
-                // Printable ASCII
-                if self.buffer.insert(c as char) {
-                    self.refresh_line()?;
-                } else {
-                    self.terminal.beep();
-                }
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            c if c >= 128 => {
-                // UTF-8 handling
-                let mut utf8_buf = vec![c];
-
-                // Determine how many bytes we need
-                let bytes_needed = if c & 0xE0 == 0xC0 {
-                    2
-                } else if c & 0xF0 == 0xE0 {
-                    3
-                } else if c & 0xF8 == 0xF0 {
-                    4
-                } else {
-                    1 // Invalid UTF-8 start byte
-                };
-
-                // Read remaining bytes
-                for _ in 1..bytes_needed {
-                    if let Ok(Some(next_byte)) = self.terminal.read_byte() {
-                        if next_byte & 0xC0 == 0x80 {
-                            utf8_buf.push(next_byte);
-                        } else {
-                            break; // Invalid continuation byte
-                        }
-                    } else {
-                        break;
-                    }
-                }
-
-                // Try to decode
-                if utf8_buf.len() == bytes_needed {
-                    if let Ok(s) = std::str::from_utf8(&utf8_buf) {
-                        if let Some(ch) = s.chars().next() {
-                            if self.buffer.insert(ch) {
-                                self.refresh_line()?;
-                            } else {
-                                self.terminal.beep();
-                            }
-                        }
-                    }
-                } else {
-                    self.terminal.beep();
-                }
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "More input needed",
-                ))
-            }
-            _ => Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "More input needed",
-            )),
+    /// Show the current line, when using the multiplexed API.
+    pub fn show(&mut self) -> io::Result<()> {
+        match self.session {
+            Some(ref mut session) => session.show(&mut self.ctx),
+            None => Ok(()),
         }
     }
-}
 
-/// Return true if the terminal name is in the list of terminals we know
-/// are not able to understand basic escape sequences.
-fn is_unsupported_term() -> bool {
-    let unsupported = ["dumb", "cons25", "emacs"];
-    if let Ok(term) = env::var("TERM") {
-        unsupported.iter().any(|&t| term.eq_ignore_ascii_case(t))
-    } else {
-        false
+    /// Return the input file descriptor of the current edit session, or
+    /// `-1` if there is none.
+    pub fn get_fd(&self) -> RawFd {
+        self.session.as_ref().map_or(-1, Session::fd)
     }
 }
 
-// Public API
-
 /// The high level function that is the main API of the linenoise library.
 /// This function checks if the terminal has basic capabilities, just checking
 /// for a blacklist of stupid terminals, and later either calls the line editing
 /// function or uses dummy `fgets()` so that you will be able to type something
 /// even in the most desperate of conditions.
+///
+/// On `None`, [`linenoise_take_error`] tells why.
 pub fn linenoise(prompt: &str) -> Option<String> {
-    let terminal = Terminal::new(libc::STDIN_FILENO, libc::STDOUT_FILENO);
-
-    if !terminal.is_tty() {
-        return linenoise_no_tty();
-    }
-
-    if is_unsupported_term() {
-        return linenoise_unsupported_term(prompt);
-    }
-
-    // Use the multiplexed API internally
-    let mut state = match LinenoiseState::edit_start(-1, -1, prompt) {
-        Ok(s) => s,
-        Err(_) => return None,
-    };
+    let mut leftover = mem::take(&mut G.lock().unwrap().leftover);
+    let result = read_line(&mut DefaultContext, &mut leftover, prompt);
+    let mut g = G.lock().unwrap();
+    g.leftover = leftover;
+    g.keep_error(result)
+}
 
-    // Read until we get a result
-    loop {
-        match state.edit_feed() {
-            Ok(Some(line)) => {
-                let _ = state.edit_stop();
-                return Some(line);
-            }
-            Ok(None) => {
-                let _ = state.edit_stop();
-                return None;
-            }
-            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
-                let _ = state.edit_stop();
-                return None;
-            }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // Need more input, continue
-                continue;
-            }
-            Err(_) => {
-                let _ = state.edit_stop();
-                return None;
-            }
-        }
-    }
+/// Take the reason the last [`linenoise`] call returned `None`, if it did:
+/// Ctrl-C, Ctrl-D, the end of the input or an I/O error.
+pub fn linenoise_take_error() -> Option<Error> {
+    G.lock().unwrap().take_error()
 }
 
 /// For when we are not a TTY
-fn linenoise_no_tty() -> Option<String> {
+fn linenoise_no_tty() -> Result<String, Error> {
     let mut line = String::new();
-    match io::stdin().read_line(&mut line) {
-        Ok(0) => None, // EOF
-        Ok(_) => {
-            // Remove trailing newline
-            if line.ends_with('\n') {
-                line.pop();
-                if line.ends_with('\r') {
-                    line.pop();
-                }
-            }
-            Some(line)
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Err(Error::Closed);
+    }
+    // Remove trailing newline
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
         }
-        Err(_) => None,
     }
+    Ok(line)
 }
 
 /// For unsupported terminals provide basic functionality
-fn linenoise_unsupported_term(prompt: &str) -> Option<String> {
-    print!("{prompt}");
+fn linenoise_unsupported_term(prompt: &str) -> Result<String, Error> {
+    print!("{}", strip_ansi(prompt));
     let _ = io::stdout().flush();
 
     linenoise_no_tty()
@@ -1145,7 +3133,56 @@ fn linenoise_unsupported_term(prompt: &str) -> Option<String> {
 
 /// Toggle multi line mode.
 pub fn linenoise_set_multi_line(ml: bool) {
-    G.lock().unwrap().multi_line = ml;
+    G.lock().unwrap().set_multi_line(ml);
+}
+
+/// Select Emacs or vi key bindings.
+pub fn linenoise_set_edit_mode(mode: EditMode) {
+    G.lock().unwrap().set_edit_mode(mode);
+}
+
+/// Set which characters make up words, for word movement and editing.
+pub fn linenoise_set_word_chars<F>(f: F)
+where
+    F: Fn(char) -> bool + Send + 'static,
+{
+    G.lock().unwrap().set_word_chars(f);
+}
+
+/// Register a validator deciding whether Enter submits the input.
+pub fn linenoise_set_validator<F>(cb: F)
+where
+    F: FnMut(&str) -> Validation + Send + 'static,
+{
+    G.lock().unwrap().set_validator(cb);
+}
+
+/// Set the prompt of the lines after the first of a multi-line input.
+pub fn linenoise_set_continuation_prompt(prompt: &str) {
+    G.lock().unwrap().set_continuation_prompt(prompt);
+}
+
+/// Turn bracketed paste mode on or off.
+pub fn linenoise_set_bracketed_paste(enable: bool) {
+    G.lock().unwrap().set_bracketed_paste(enable);
+}
+
+/// Choose what is done with the line breaks in pasted text.
+pub fn linenoise_set_paste_newlines(newlines: PasteNewlines) {
+    G.lock().unwrap().set_paste_newlines(newlines);
+}
+
+/// Register a callback rewriting pasted text before it is inserted.
+pub fn linenoise_set_paste_filter<F>(f: F)
+where
+    F: FnMut(&str) -> String + Send + 'static,
+{
+    G.lock().unwrap().set_paste_filter(f);
+}
+
+/// Set how long to wait for the rest of an escape sequence after an Esc.
+pub fn linenoise_set_esc_timeout(timeout: Duration) {
+    G.lock().unwrap().set_esc_timeout(timeout);
 }
 
 /// Enable mask mode. When it is enabled, instead of the input that
@@ -1153,28 +3190,99 @@ pub fn linenoise_set_multi_line(ml: bool) {
 /// number of asterisks, like "***". This is useful for passwords and
 /// other secrets that should not be displayed.
 pub fn linenoise_mask_mode_enable() {
-    G.lock().unwrap().mask_mode = true;
+    G.lock().unwrap().set_mask_mode(true);
 }
 
 /// Disable mask mode.
 pub fn linenoise_mask_mode_disable() {
-    G.lock().unwrap().mask_mode = false;
+    G.lock().unwrap().set_mask_mode(false);
 }
 
 /// Register a callback function to be called for tab-completion.
-pub fn linenoise_set_completion_callback(cb: CompletionCallback) {
-    G.lock().unwrap().completion_callback = Some(cb);
+pub fn linenoise_set_completion_callback<F>(cb: F)
+where
+    F: FnMut(&str, &mut Vec<String>) + Send + 'static,
+{
+    G.lock().unwrap().set_completion_callback(cb);
+}
+
+/// Register a context-aware completion callback. See
+/// [`Linenoise::set_completer`].
+pub fn linenoise_set_completer<F>(cb: F)
+where
+    F: FnMut(&str, usize, &mut Vec<Candidate>) + Send + 'static,
+{
+    G.lock().unwrap().set_completer(cb);
+}
+
+/// Select how Tab presents completion candidates.
+pub fn linenoise_set_completion_mode(mode: CompletionMode) {
+    G.lock().unwrap().set_completion_mode(mode);
+}
+
+/// Make the first Tab insert the longest common prefix of all candidates.
+pub fn linenoise_set_completion_prefix(enable: bool) {
+    G.lock().unwrap().set_completion_prefix(enable);
 }
 
 /// Registers a hints function to be called to show hints to the user
 /// at the right of the prompt.
-pub fn linenoise_set_hints_callback(cb: HintsCallback) {
-    G.lock().unwrap().hints_callback = Some(cb);
+pub fn linenoise_set_hints_callback<F>(cb: F)
+where
+    F: FnMut(&str) -> Option<(String, i32, bool)> + Send + 'static,
+{
+    G.lock().unwrap().set_hints_callback(cb);
+}
+
+/// Register a hints function returning a styled [`Hint`].
+pub fn linenoise_set_hinter<F>(cb: F)
+where
+    F: FnMut(&str) -> Option<Hint> + Send + 'static,
+{
+    G.lock().unwrap().set_hinter(cb);
+}
+
+/// Register a highlighter returning the styled spans of the line.
+pub fn linenoise_set_highlighter<F>(cb: F)
+where
+    F: FnMut(&str, usize) -> Vec<Span> + Send + 'static,
+{
+    G.lock().unwrap().set_highlighter(cb);
+}
+
+/// Make Up/Down only step through history entries starting with the text
+/// typed before navigation began.
+pub fn linenoise_set_history_prefix_search(enable: bool) {
+    G.lock().unwrap().set_history_prefix_search(enable);
+}
+
+/// Toggle fish style autosuggestions from history.
+pub fn linenoise_set_autosuggest(enable: bool) {
+    G.lock().unwrap().set_autosuggest(enable);
+}
+
+/// Bind a key, or a sequence of keys, to an editor command.
+pub fn linenoise_bind_key(keys: &[KeyEvent], cmd: Cmd) {
+    G.lock().unwrap().bind_key(keys, cmd);
+}
+
+/// Bind a key, or a sequence of keys, to a closure changing the line and
+/// the cursor position.
+pub fn linenoise_bind_key_fn<F>(keys: &[KeyEvent], f: F)
+where
+    F: FnMut(&mut String, &mut usize) + Send + 'static,
+{
+    G.lock().unwrap().bind_key_fn(keys, f);
+}
+
+/// Remove the binding of a key or a sequence of keys.
+pub fn linenoise_unbind_key(keys: &[KeyEvent]) {
+    G.lock().unwrap().unbind_key(keys);
 }
 
 /// This is the API call to add a new entry to the linenoise history.
 pub fn linenoise_history_add(line: &str) -> bool {
-    G.lock().unwrap().history.add(line)
+    G.lock().unwrap().history_add(line)
 }
 
 /// Set the maximum length for the history. This function can be called
@@ -1182,25 +3290,12 @@ pub fn linenoise_history_add(line: &str) -> bool {
 /// to retain just the latest `len` elements if the new history length
 /// value is smaller than the amount of items already inside the history.
 pub fn linenoise_history_set_max_len(len: usize) -> bool {
-    if len < 1 {
-        return false;
-    }
-    let mut state = G.lock().unwrap();
-    state.history.max_len = len;
-    while state.history.entries.len() > len {
-        state.history.entries.pop_front();
-    }
-    true
+    G.lock().unwrap().history_set_max_len(len)
 }
 
 /// Save the history to the specified file.
 pub fn linenoise_history_save(filename: &str) -> io::Result<()> {
-    let state = G.lock().unwrap();
-    let mut file = File::create(filename)?;
-    for entry in &state.history.entries {
-        writeln!(file, "{entry}")?;
-    }
-    Ok(())
+    G.lock().unwrap().history_save(filename)
 }
 
 /// Load the history from the specified file. If the file does not exist
@@ -1208,47 +3303,28 @@ pub fn linenoise_history_save(filename: &str) -> io::Result<()> {
 ///
 /// If file exists then it returns `Ok()` on success and an error on fail.
 pub fn linenoise_history_load(filename: &str) -> io::Result<()> {
-    let file = match File::open(filename) {
-        Ok(f) => f,
-        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
-        Err(e) => return Err(e),
-    };
-
-    let reader = BufReader::new(file);
-    let mut state = G.lock().unwrap();
-
-    #[allow(clippy::manual_flatten)]
-    for line in reader.lines() {
-        if let Ok(line) = line {
-            let trimmed = line.trim_end();
-            if !trimmed.is_empty() {
-                state.history.add(trimmed);
-            }
-        }
-    }
-
-    Ok(())
+    G.lock().unwrap().history_load(filename)
 }
 
 /// Clear the screen. Used to handle Ctrl+L
 pub fn linenoise_clear_screen() {
-    let terminal = Terminal::new(libc::STDIN_FILENO, libc::STDOUT_FILENO);
-    let _ = terminal.clear_screen();
+    let mut terminal = FdTerminal::new(libc::STDIN_FILENO, libc::STDOUT_FILENO);
+    let _ = terminal.write(b"\x1b[H\x1b[2J");
 }
 
 /// This special mode is used by linenoise in order to print scan codes
 /// on screen for debugging/development purposes. It is implemented by
 /// the linenoise example program using the `--keycodes` option.
 pub fn linenoise_print_key_codes() {
-    let terminal = Terminal::new(libc::STDIN_FILENO, libc::STDOUT_FILENO);
+    // Raw mode lasts as long as the terminal
+    let mut terminal = FdTerminal::new(libc::STDIN_FILENO, libc::STDOUT_FILENO);
 
     println!("Linenoise key codes debugging mode.");
     println!("Press keys to see scan codes. Type 'quit' to exit.");
 
-    let _guard = match terminal.enable_raw_mode() {
-        Ok(guard) => guard,
-        Err(_) => return,
-    };
+    if terminal.enable_raw_mode().is_err() {
+        return;
+    }
 
     let mut quit_buf = [0u8; 4];
 
@@ -1275,6 +3351,7 @@ pub fn linenoise_print_key_codes() {
                 4 => output.push_str(" (ctrl-d)"),
                 5 => output.push_str(" (ctrl-e)"),
                 6 => output.push_str(" (ctrl-f)"),
+                7 => output.push_str(" (ctrl-g)"),
                 8 => output.push_str(" (ctrl-h)"),
                 9 => output.push_str(" (tab)"),
                 11 => output.push_str(" (ctrl-k)"),
@@ -1282,16 +3359,21 @@ pub fn linenoise_print_key_codes() {
                 13 => output.push_str(" (enter)"),
                 14 => output.push_str(" (ctrl-n)"),
                 16 => output.push_str(" (ctrl-p)"),
+                18 => output.push_str(" (ctrl-r)"),
+                19 => output.push_str(" (ctrl-s)"),
                 20 => output.push_str(" (ctrl-t)"),
                 21 => output.push_str(" (ctrl-u)"),
                 23 => output.push_str(" (ctrl-w)"),
+                24 => output.push_str(" (ctrl-x)"),
+                25 => output.push_str(" (ctrl-y)"),
                 27 => output.push_str(" (esc)"),
+                31 => output.push_str(" (ctrl-_)"),
                 127 => output.push_str(" (backspace)"),
                 _ => {}
             }
 
             // Use write to avoid println's processing, add explicit \r\n
-            let _ = terminal.write(&format!("{}\r\n", output));
+            let _ = terminal.write(format!("{}\r\n", output).as_bytes());
 
             if &quit_buf == b"quit" {
                 break;
@@ -1299,15 +3381,19 @@ pub fn linenoise_print_key_codes() {
         }
     }
 
-    // _guard will be dropped here, terminal returns to cooked mode
+    // terminal will be dropped here, terminal returns to cooked mode
     println!();
 }
 
-/// Multiplexing support
+/// Multiplexing support for the default instance used by the `linenoise_*`
+/// free functions. See [`Linenoise::edit_start`] for the per-instance API.
+///
+/// Each state edits its own line, with the settings, history and callbacks
+/// of the default instance. Its lock is only held while they are used, so
+/// callbacks can call the `linenoise_*` functions. Input read past the end
+/// of the line stays with the state, never reaching another one.
 pub struct LinenoiseState {
-    editor: Editor,
-    active: bool,
-    _raw_guard: Option<RawModeGuard>,
+    session: Option<Session>,
 }
 
 impl LinenoiseState {
@@ -1315,103 +3401,67 @@ impl LinenoiseState {
     /// to implement the blocking variant of the API but can also be called by the user
     /// directly in an event-driven program.
     pub fn edit_start(stdin_fd: RawFd, stdout_fd: RawFd, prompt: &str) -> io::Result<Self> {
-        let ifd = if stdin_fd == -1 {
-            libc::STDIN_FILENO
-        } else {
-            stdin_fd
-        };
-
-        let ofd = if stdout_fd == -1 {
-            libc::STDOUT_FILENO
-        } else {
-            stdout_fd
-        };
-
-        let terminal = Terminal::new(ifd, ofd);
-
-        if !terminal.is_tty() || is_unsupported_term() {
-            return Err(io::Error::other("Not supported"));
-        }
-
-        let raw_guard = terminal.enable_raw_mode()?;
-
-        let mut editor = Editor::new(terminal, prompt);
-
-        // Reset editor state for new session
-        editor.history_index = 0;
-        editor.saved_line = None;
-
-        // Display initial prompt
-        editor.refresh_line()?;
+        let terminal = open_terminal(stdin_fd, stdout_fd)?;
+        Self::edit_start_with(terminal, prompt)
+    }
 
+    /// Part of the multiplexed API: start editing a line like
+    /// [`edit_start`](Self::edit_start), on any terminal backend.
+    pub fn edit_start_with<T>(terminal: T, prompt: &str) -> io::Result<Self>
+    where
+        T: TerminalBackend + 'static,
+    {
+        let session = DefaultContext
+            .with(|ctx| Session::start(ctx, Box::new(terminal), prompt, Leftover::default()))?;
         Ok(Self {
-            editor,
-            active: true,
-            _raw_guard: Some(raw_guard),
+            session: Some(session),
         })
     }
 
     /// Part of the multiplexed API. Call this function each time there is some data
     /// to read from the standard input file descriptor. In case of blocking operations
     /// this function can just be called in a loop, and block.
-    pub fn edit_feed(&mut self) -> io::Result<Option<String>> {
-        if !self.active {
-            return Ok(None);
+    pub fn edit_feed(&mut self) -> Result<FeedResult, Error> {
+        match self.session {
+            Some(ref mut session) => session.feed(&mut DefaultContext),
+            None => Ok(FeedResult::Eof),
         }
+    }
 
-        // Try to read a byte
-        match self.editor.terminal.read_byte()? {
-            Some(c) => {
-                match self.editor.process_key(c) {
-                    Ok(result) => {
-                        if result.is_some() {
-                            // Move to new line before returning
-                            let _ = self.editor.terminal.write("\r\n");
-                            self.active = false;
-                        }
-                        Ok(result)
-                    }
-                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {
-                        self.active = false;
-                        Err(e)
-                    }
-                    Err(e) => Err(e),
-                }
-            }
-            None => {
-                // EOF
-                self.active = false;
-                Ok(None)
-            }
+    /// Part of the multiplexed API, for programs reading the input
+    /// themselves. See [`Linenoise::feed_bytes`].
+    pub fn feed_bytes(&mut self, bytes: &[u8]) -> Result<FeedResult, Error> {
+        match self.session {
+            Some(ref mut session) => DefaultContext.with(|ctx| session.feed_bytes(ctx, bytes)),
+            None => Ok(FeedResult::Eof),
         }
     }
 
     /// Part of the multiplexed API. At this point the user input is in the buffer,
     /// and we can restore the terminal in normal node.
     pub fn edit_stop(&mut self) -> io::Result<()> {
-        if self.active {
-            self.active = false;
-            // Drop the guard to restore terminal
-            self._raw_guard = None;
-        }
+        // Dropping the session restores the terminal
+        self.session = None;
         Ok(())
     }
 
     /// Hide the current line, when using the multiplexed API.
-    pub fn hide(&self) -> io::Result<()> {
-        // Move to beginning of line and clear it
-        self.editor.terminal.write("\r\x1b[0K")
+    pub fn hide(&mut self) -> io::Result<()> {
+        match self.session {
+            Some(ref mut session) => session.hide(),
+            None => Ok(()),
+        }
     }
 
     /// Show the current line, when using the multiplexed API.
     pub fn show(&mut self) -> io::Result<()> {
-        // Instead of restoring cursor position which might be stale,
-        // just move to beginning of line and refresh
-        self.editor.terminal.write("\r")?;
-        self.editor.refresh_line()
+        match self.session {
+            Some(ref mut session) => DefaultContext.with(|ctx| session.show(ctx)),
+            None => Ok(()),
+        }
     }
 
     pub fn get_fd(&self) -> RawFd {
-        self.editor.terminal.ifd
+        self.session.as_ref().map_or(-1, Session::fd)
     }
 }
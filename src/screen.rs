@@ -0,0 +1,394 @@
+//! A virtual VT100 screen, showing what the editor's output would look
+//! like on a terminal.
+
+use std::mem;
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::{Color, Style};
+
+/// One character cell of a [`Screen`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cell {
+    /// The character in the cell, with its combining marks. A blank cell
+    /// holds a space, and the right half of a wide character nothing.
+    pub text: String,
+    pub style: Style,
+    /// Reverse video, which [`Style`] has no field for.
+    pub reverse: bool,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            text: " ".to_string(),
+            style: Style::new(),
+            reverse: false,
+        }
+    }
+}
+
+impl Cell {
+    /// Whether the cell is the right half of a wide character.
+    fn is_continuation(&self) -> bool {
+        self.text.is_empty()
+    }
+}
+
+/// Where the parser is in an escape sequence.
+#[derive(Clone, Debug)]
+enum State {
+    Ground,
+    Esc,
+    /// Parameter and intermediate bytes of a CSI sequence so far.
+    Csi(String),
+    Osc,
+    /// An ESC in an OSC sequence, starting its ST.
+    OscEsc,
+}
+
+/// A grid of character cells that output is written to, like a VT100 or
+/// xterm would: it moves the cursor, erases lines and the screen, tracks
+/// SGR styles and wraps and scrolls lines, measuring characters by their
+/// display width. Other escape sequences are skipped.
+///
+/// A line that fills the last column wraps only when the next character
+/// comes, as on xterm.
+///
+/// ```
+/// use linenoise_rs::Screen;
+///
+/// let mut screen = Screen::new(10, 3);
+/// screen.feed("> héllo\x1b[3D".as_bytes());
+/// assert_eq!(screen.row_text(0), "> héllo");
+/// assert_eq!(screen.cursor(), (0, 4));
+///
+/// screen.feed(b"\r\x1b[0K> abcdefghij");
+/// assert_eq!(screen.lines(), ["> abcdefgh", "ij", ""]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Screen {
+    columns: usize,
+    grid: Vec<Vec<Cell>>,
+    /// Row and column of the cursor.
+    cursor: (usize, usize),
+    /// The last column was written to, so the next character goes on the
+    /// next row.
+    wrap_pending: bool,
+    style: Style,
+    reverse: bool,
+    state: State,
+    /// Bytes of a UTF-8 character so far.
+    utf8: Vec<u8>,
+}
+
+impl Screen {
+    /// A blank screen of `columns` by `rows` cells, with the cursor at its
+    /// top left.
+    pub fn new(columns: usize, rows: usize) -> Self {
+        let columns = columns.max(1);
+        Screen {
+            columns,
+            grid: vec![vec![Cell::default(); columns]; rows.max(1)],
+            cursor: (0, 0),
+            wrap_pending: false,
+            style: Style::new(),
+            reverse: false,
+            state: State::Ground,
+            utf8: Vec::new(),
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.grid.len()
+    }
+
+    /// Row and column of the cursor, counted from 0.
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    /// The cell at `row` and `column`, if it is on the screen.
+    pub fn cell(&self, row: usize, column: usize) -> Option<&Cell> {
+        self.grid.get(row)?.get(column)
+    }
+
+    /// The text on a row, without trailing blanks.
+    pub fn row_text(&self, row: usize) -> String {
+        let Some(cells) = self.grid.get(row) else {
+            return String::new();
+        };
+        let text: String = cells.iter().map(|cell| cell.text.as_str()).collect();
+        text.trim_end_matches(' ').to_string()
+    }
+
+    /// The text on every row, without trailing blanks.
+    pub fn lines(&self) -> Vec<String> {
+        (0..self.rows()).map(|row| self.row_text(row)).collect()
+    }
+
+    /// Change the size of the screen, cutting or padding the rows at their
+    /// end. Lines aren't wrapped again.
+    pub fn resize(&mut self, columns: usize, rows: usize) {
+        self.columns = columns.max(1);
+        self.grid.resize(rows.max(1), Vec::new());
+        for cells in &mut self.grid {
+            cells.resize(self.columns, Cell::default());
+        }
+        self.cursor.0 = self.cursor.0.min(self.rows() - 1);
+        self.cursor.1 = self.cursor.1.min(self.columns - 1);
+        self.wrap_pending = false;
+    }
+
+    /// Process output written to the terminal.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.feed_byte(byte);
+        }
+    }
+
+    fn feed_byte(&mut self, byte: u8) {
+        match mem::replace(&mut self.state, State::Ground) {
+            State::Ground => self.ground(byte),
+            State::Esc => match byte {
+                b'[' => self.state = State::Csi(String::new()),
+                b']' => self.state = State::Osc,
+                // Two character sequences are skipped
+                _ => {}
+            },
+            State::Csi(mut params) => match byte {
+                0x40..=0x7e => self.csi(&params, byte),
+                _ => {
+                    params.push(byte as char);
+                    self.state = State::Csi(params);
+                }
+            },
+            State::Osc => match byte {
+                0x07 => {}
+                0x1b => self.state = State::OscEsc,
+                _ => self.state = State::Osc,
+            },
+            // The ST is ESC \, but any ESC ends the sequence
+            State::OscEsc => {}
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        if !self.utf8.is_empty() || byte >= 0x80 {
+            self.utf8.push(byte);
+            match std::str::from_utf8(&self.utf8) {
+                Ok(s) => {
+                    let c = s.chars().next().unwrap_or(char::REPLACEMENT_CHARACTER);
+                    self.utf8.clear();
+                    self.print(c);
+                }
+                Err(err) if err.error_len().is_some() => {
+                    self.utf8.clear();
+                    self.print(char::REPLACEMENT_CHARACTER);
+                }
+                // Wait for the rest of the character
+                Err(_) => {}
+            }
+            return;
+        }
+
+        match byte {
+            0x1b => self.state = State::Esc,
+            b'\r' => self.move_to(self.cursor.0, 0),
+            b'\n' => {
+                self.line_feed();
+                self.wrap_pending = false;
+            }
+            0x08 => self.move_to(self.cursor.0, self.cursor.1.saturating_sub(1)),
+            b'\t' => self.move_to(self.cursor.0, (self.cursor.1 / 8 + 1) * 8),
+            0x20..=0x7e => self.print(byte as char),
+            // The bell and other controls show nothing
+            _ => {}
+        }
+    }
+
+    /// Write a character at the cursor and move past it.
+    fn print(&mut self, c: char) {
+        let width = c.width().unwrap_or(0);
+        if width == 0 {
+            // A combining mark goes with the character before it
+            let (row, mut col) = self.cursor;
+            if !self.wrap_pending {
+                col = col.saturating_sub(1);
+            }
+            while col > 0 && self.grid[row][col].is_continuation() {
+                col -= 1;
+            }
+            self.grid[row][col].text.push(c);
+            return;
+        }
+        if width > self.columns {
+            return;
+        }
+
+        if self.wrap_pending || self.cursor.1 + width > self.columns {
+            self.cursor.1 = 0;
+            self.line_feed();
+        }
+        self.wrap_pending = false;
+
+        let (row, col) = self.cursor;
+        self.clear_wide(row, col);
+        self.clear_wide(row, col + width - 1);
+        let cell = Cell {
+            text: c.to_string(),
+            style: self.style,
+            reverse: self.reverse,
+        };
+        if width == 2 {
+            self.grid[row][col + 1] = Cell {
+                text: String::new(),
+                ..cell.clone()
+            };
+        }
+        self.grid[row][col] = cell;
+
+        if col + width < self.columns {
+            self.cursor.1 = col + width;
+        } else {
+            self.cursor.1 = self.columns - 1;
+            self.wrap_pending = true;
+        }
+    }
+
+    /// Blank both halves of a wide character one of which is at `col`,
+    /// before half of it is overwritten.
+    fn clear_wide(&mut self, row: usize, col: usize) {
+        let cells = &mut self.grid[row];
+        if cells[col].is_continuation() {
+            cells[col] = Cell::default();
+            if col > 0 {
+                cells[col - 1] = Cell::default();
+            }
+        } else if cells
+            .get(col + 1)
+            .is_some_and(|cell| cell.is_continuation())
+        {
+            cells[col] = Cell::default();
+            cells[col + 1] = Cell::default();
+        }
+    }
+
+    /// Move down a row, scrolling the screen up at its bottom.
+    fn line_feed(&mut self) {
+        if self.cursor.0 + 1 < self.rows() {
+            self.cursor.0 += 1;
+        } else {
+            self.grid.remove(0);
+            self.grid.push(vec![Cell::default(); self.columns]);
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.cursor = (row.min(self.rows() - 1), col.min(self.columns - 1));
+        self.wrap_pending = false;
+    }
+
+    fn csi(&mut self, params: &str, command: u8) {
+        // Private modes, like bracketed paste, change nothing on screen
+        if params.starts_with('?') {
+            return;
+        }
+        let args: Vec<usize> = params.split(';').map(|p| p.parse().unwrap_or(0)).collect();
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        let count = arg(0).max(1);
+        let (row, col) = self.cursor;
+
+        match command {
+            b'A' => self.move_to(row.saturating_sub(count), col),
+            b'B' => self.move_to(row + count, col),
+            b'C' => self.move_to(row, col + count),
+            b'D' => self.move_to(row, col.saturating_sub(count)),
+            b'G' => self.move_to(row, count - 1),
+            b'H' | b'f' => self.move_to(count - 1, arg(1).max(1) - 1),
+            b'J' => {
+                let (before, after) = match arg(0) {
+                    0 => (false, true),
+                    1 => (true, false),
+                    _ => (true, true),
+                };
+                if before {
+                    for cells in &mut self.grid[..row] {
+                        cells.fill(Cell::default());
+                    }
+                }
+                if after {
+                    for cells in &mut self.grid[row + 1..] {
+                        cells.fill(Cell::default());
+                    }
+                }
+                self.erase_in_line(arg(0));
+            }
+            b'K' => self.erase_in_line(arg(0)),
+            b'm' => self.select_graphic_rendition(&args),
+            // Cursor position reports and the rest aren't answered
+            _ => {}
+        }
+    }
+
+    /// Erase to the end of the cursor's row with 0, to its start with 1,
+    /// or the whole row.
+    fn erase_in_line(&mut self, mode: usize) {
+        let (row, col) = self.cursor;
+        let cells = &mut self.grid[row];
+        let range = match mode {
+            0 => col..cells.len(),
+            1 => 0..col + 1,
+            _ => 0..cells.len(),
+        };
+        cells[range].fill(Cell::default());
+    }
+
+    fn select_graphic_rendition(&mut self, args: &[usize]) {
+        let mut args = args.iter().copied();
+        while let Some(arg) = args.next() {
+            match arg {
+                0 => {
+                    self.style = Style::new();
+                    self.reverse = false;
+                }
+                1 => self.style.bold = true,
+                2 => self.style.dim = true,
+                3 => self.style.italic = true,
+                4 => self.style.underline = true,
+                7 => self.reverse = true,
+                22 => {
+                    self.style.bold = false;
+                    self.style.dim = false;
+                }
+                23 => self.style.italic = false,
+                24 => self.style.underline = false,
+                27 => self.reverse = false,
+                30..=37 | 90..=97 => self.style.fg = Color::from_sgr(arg as i32),
+                40..=47 | 100..=107 => self.style.bg = Color::from_sgr(arg as i32 - 10),
+                38 | 48 => {
+                    let color = match args.next() {
+                        Some(5) => args.next().map(|index| Color::Ansi256(index as u8)),
+                        Some(2) => {
+                            let mut rgb = || args.next().unwrap_or(0) as u8;
+                            Some(Color::Rgb(rgb(), rgb(), rgb()))
+                        }
+                        _ => None,
+                    };
+                    if arg == 38 {
+                        self.style.fg = color;
+                    } else {
+                        self.style.bg = color;
+                    }
+                }
+                39 => self.style.fg = None,
+                49 => self.style.bg = None,
+                _ => {}
+            }
+        }
+    }
+}
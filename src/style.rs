@@ -0,0 +1,321 @@
+//! Text styles, and measuring text that contains escape sequences.
+
+use std::ops::Range;
+
+use unicode_width::UnicodeWidthStr;
+
+/// A terminal color: one of the 16 basic ones, which the terminal's theme
+/// may change, an index into the 256 color palette, or a 24-bit color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    BrightBlack,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+    Ansi256(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// The basic colors, in the order of their SGR codes.
+const BASIC_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::White,
+    Color::BrightBlack,
+    Color::BrightRed,
+    Color::BrightGreen,
+    Color::BrightYellow,
+    Color::BrightBlue,
+    Color::BrightMagenta,
+    Color::BrightCyan,
+    Color::BrightWhite,
+];
+
+impl Color {
+    /// The basic color set by an SGR foreground parameter, 30 to 37 or 90
+    /// to 97.
+    pub fn from_sgr(code: i32) -> Option<Color> {
+        match code {
+            30..=37 => Some(BASIC_COLORS[code as usize - 30]),
+            90..=97 => Some(BASIC_COLORS[code as usize - 90 + 8]),
+            _ => None,
+        }
+    }
+
+    /// Append the SGR parameters selecting the color as foreground, or as
+    /// background with `bg`.
+    fn write_params(self, params: &mut Vec<String>, bg: bool) {
+        let (base, bright, extended) = if bg { (40, 100, 48) } else { (30, 90, 38) };
+        match self {
+            Color::Ansi256(index) => params.push(format!("{extended};5;{index}")),
+            Color::Rgb(r, g, b) => params.push(format!("{extended};2;{r};{g};{b}")),
+            basic => {
+                let index = BASIC_COLORS.iter().position(|&c| c == basic).unwrap_or(0) as u8;
+                if index < 8 {
+                    params.push((base + index).to_string());
+                } else {
+                    params.push((bright + index - 8).to_string());
+                }
+            }
+        }
+    }
+}
+
+/// How a piece of text is displayed. The default is the terminal's own.
+///
+/// ```
+/// use linenoise_rs::{Color, Style};
+///
+/// let style = Style::new().fg(Color::Green).bold();
+/// assert_eq!(style.paint("ok"), "\x1b[1;32mok\x1b[0m");
+///
+/// let style = Style::new().fg(Color::Rgb(255, 128, 0)).bg(Color::Ansi256(236));
+/// assert_eq!(style.prefix(), "\x1b[38;2;255;128;0;48;5;236m");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    pub const fn new() -> Self {
+        Style {
+            fg: None,
+            bg: None,
+            bold: false,
+            dim: false,
+            italic: false,
+            underline: false,
+        }
+    }
+
+    /// Set the foreground color.
+    pub const fn fg(mut self, color: Color) -> Self {
+        self.fg = Some(color);
+        self
+    }
+
+    /// Set the background color.
+    pub const fn bg(mut self, color: Color) -> Self {
+        self.bg = Some(color);
+        self
+    }
+
+    pub const fn bold(mut self) -> Self {
+        self.bold = true;
+        self
+    }
+
+    /// Faint text, which terminals draw with a lighter color.
+    pub const fn dim(mut self) -> Self {
+        self.dim = true;
+        self
+    }
+
+    pub const fn italic(mut self) -> Self {
+        self.italic = true;
+        self
+    }
+
+    pub const fn underline(mut self) -> Self {
+        self.underline = true;
+        self
+    }
+
+    /// Whether the style leaves text as the terminal displays it anyway.
+    pub fn is_plain(&self) -> bool {
+        *self == Style::new()
+    }
+
+    /// The escape sequence switching to the style, empty for a plain one.
+    pub fn prefix(&self) -> String {
+        let mut params = Vec::new();
+        for (set, param) in [
+            (self.bold, "1"),
+            (self.dim, "2"),
+            (self.italic, "3"),
+            (self.underline, "4"),
+        ] {
+            if set {
+                params.push(param.to_string());
+            }
+        }
+        if let Some(fg) = self.fg {
+            fg.write_params(&mut params, false);
+        }
+        if let Some(bg) = self.bg {
+            bg.write_params(&mut params, true);
+        }
+        if params.is_empty() {
+            return String::new();
+        }
+
+        format!("\x1b[{}m", params.join(";"))
+    }
+
+    /// `text` in this style, followed by a reset to the default one.
+    pub fn paint(&self, text: &str) -> String {
+        if self.is_plain() {
+            text.to_string()
+        } else {
+            format!("{}{text}\x1b[0m", self.prefix())
+        }
+    }
+}
+
+/// A hint shown after the line as it is typed, returned by a hints
+/// callback. It is drawn at the right of the line, or on the row below it
+/// like a help text with [`Hint::below`]. Only its first line is shown.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hint {
+    pub text: String,
+    pub style: Style,
+    pub below: bool,
+}
+
+impl Hint {
+    pub fn new(text: impl Into<String>) -> Self {
+        Hint {
+            text: text.into(),
+            style: Style::new(),
+            below: false,
+        }
+    }
+
+    pub fn style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Show the hint on the row below the line rather than at its right.
+    pub fn below(mut self) -> Self {
+        self.below = true;
+        self
+    }
+}
+
+/// A styled part of the line, returned by a highlighter callback.
+///
+/// `range` is the span of the line, in characters, displayed in `style`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub range: Range<usize>,
+    pub style: Style,
+}
+
+impl Span {
+    pub fn new(range: Range<usize>, style: Style) -> Self {
+        Span { range, style }
+    }
+}
+
+/// Builds a prompt out of plain and styled pieces, so that the escape
+/// sequences don't have to be written by hand.
+///
+/// ```
+/// use linenoise_rs::{Color, PromptBuilder, Style};
+///
+/// let prompt = PromptBuilder::new()
+///     .styled("~/src", Style::new().fg(Color::Blue))
+///     .text(" ")
+///     .styled("λ", Style::new().fg(Color::Green).bold())
+///     .text(" ")
+///     .build();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PromptBuilder {
+    prompt: String,
+}
+
+impl PromptBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append text in the default style.
+    pub fn text(mut self, text: &str) -> Self {
+        self.prompt.push_str(text);
+        self
+    }
+
+    /// Append text in `style`.
+    pub fn styled(mut self, text: &str, style: Style) -> Self {
+        self.prompt.push_str(&style.paint(text));
+        self
+    }
+
+    /// The prompt, to pass to `readline()`.
+    pub fn build(self) -> String {
+        self.prompt
+    }
+}
+
+/// `s` without its escape sequences: CSI ones like the SGR `ESC [ 1 m`,
+/// OSC ones like the window title `ESC ] 0 ; title BEL`, and the two
+/// character ones.
+pub(super) fn strip_ansi(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // Parameters and intermediate bytes up to the final byte
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // Up to a BEL or an ST, ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' {
+                        chars.next_if_eq(&'\\');
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// The terminal columns taken by `s`, not counting escape sequences.
+pub(super) fn visible_width(s: &str) -> usize {
+    if s.contains('\x1b') {
+        strip_ansi(s).width()
+    } else {
+        s.width()
+    }
+}
@@ -0,0 +1,387 @@
+//! Terminal backends: the input, output, raw mode and size of the terminal
+//! a line is edited on.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libc::{c_void, tcgetattr, tcsetattr, termios};
+
+use crate::Screen;
+
+/// What the editor needs of a terminal. The editor runs on a tty by
+/// default; [`Linenoise::edit_start_with`](crate::Linenoise::edit_start_with)
+/// runs it on any implementation, like [`HeadlessTerminal`] in tests.
+pub trait TerminalBackend: Send {
+    /// Read the input there is, up to the size of `buf`, waiting for some
+    /// if there is none. Returns 0 at the end of the input.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Wait up to `timeout` for input, returning whether there is some.
+    fn wait_input(&mut self, timeout: Duration) -> io::Result<bool>;
+
+    /// Write all of `buf`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// The width of the terminal in columns.
+    fn columns(&mut self) -> usize;
+
+    /// Switch to raw mode, where input is read byte by byte, unechoed and
+    /// with no special keys.
+    fn enable_raw_mode(&mut self) -> io::Result<()>;
+
+    /// Switch back to the mode before [`enable_raw_mode`](Self::enable_raw_mode).
+    fn disable_raw_mode(&mut self) -> io::Result<()>;
+
+    /// The file descriptor input is read from, if there is one, for
+    /// programs waiting for input with `select()` or `poll()`.
+    fn input_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// A tty on a pair of file descriptors.
+pub(super) struct FdTerminal {
+    ifd: RawFd,
+    ofd: RawFd,
+    /// The mode to restore, while in raw mode.
+    orig_termios: Option<termios>,
+}
+
+impl FdTerminal {
+    pub(super) fn new(ifd: RawFd, ofd: RawFd) -> Self {
+        FdTerminal {
+            ifd,
+            ofd,
+            orig_termios: None,
+        }
+    }
+
+    pub(super) fn is_tty(&self) -> bool {
+        unsafe { libc::isatty(self.ifd) != 0 }
+    }
+
+    pub(super) fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut c = [0u8];
+        Ok(if self.read(&mut c)? == 0 {
+            None
+        } else {
+            Some(c[0])
+        })
+    }
+
+    /// Use the ESC [6n escape sequence to query the horizontal cursor position
+    /// and return it.
+    fn get_cursor_position(&mut self) -> io::Result<(usize, usize)> {
+        self.write(b"\x1b[6n")?;
+
+        let mut buf = [0u8; 32];
+        let mut i = 0;
+
+        while i < buf.len() - 1 {
+            buf[i] = self.read_byte()?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "EOF reading cursor")
+            })?;
+            i += 1;
+            if buf[i - 1] == b'R' {
+                break;
+            }
+        }
+
+        // Parse response
+        let response =
+            std::str::from_utf8(&buf[2..i - 1]).map_err(|_| io::Error::other("Invalid UTF-8"))?;
+
+        let (rows, cols) = response
+            .split_once(';')
+            .ok_or_else(|| io::Error::other("Invalid format"))?;
+
+        Ok((
+            rows.parse().map_err(|_| io::Error::other("Invalid row"))?,
+            cols.parse().map_err(|_| io::Error::other("Invalid col"))?,
+        ))
+    }
+}
+
+impl TerminalBackend for FdTerminal {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = unsafe { libc::read(self.ifd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+            if n == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            return Ok(n as usize);
+        }
+    }
+
+    fn wait_input(&mut self, timeout: Duration) -> io::Result<bool> {
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let mut pfd = libc::pollfd {
+            fd: self.ifd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            if unsafe { libc::poll(&mut pfd, 1, timeout) } == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            return Ok(pfd.revents != 0);
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            match unsafe {
+                libc::write(
+                    self.ofd,
+                    buf[written..].as_ptr() as *const c_void,
+                    buf.len() - written,
+                )
+            } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                0 => break,
+                n => written += n as usize,
+            }
+        }
+        if self.ofd == libc::STDOUT_FILENO {
+            io::stdout().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Try to get the number of columns in the current terminal, or assume 80
+    /// if it fails.
+    fn columns(&mut self) -> usize {
+        // First try with ioctl
+        unsafe {
+            let mut ws: libc::winsize = mem::zeroed();
+            if libc::ioctl(1, libc::TIOCGWINSZ, &mut ws) == 0 && ws.ws_col != 0 {
+                return ws.ws_col as usize;
+            }
+        }
+
+        // ioctl() failed. Try to query the terminal itself.
+        // This is the fallback method from the original linenoise.
+
+        // Get the initial position so we can restore it later
+        let orig_pos = match self.get_cursor_position() {
+            Ok(pos) => pos,
+            Err(_) => return 80,
+        };
+
+        // Go to right margin and get position
+        if self.write(b"\x1b[999C").is_err() {
+            return 80;
+        }
+
+        let cols = match self.get_cursor_position() {
+            Ok(pos) => pos.1,
+            Err(_) => 80,
+        };
+
+        // Restore position
+        if orig_pos != (0, 0) {
+            let _ = self.write(format!("\x1b[{};{}H", orig_pos.0, orig_pos.1).as_bytes());
+        }
+
+        cols
+    }
+
+    /// Raw mode: 1960 magic shit.
+    fn enable_raw_mode(&mut self) -> io::Result<()> {
+        if !self.is_tty() {
+            return Err(io::Error::other("Not a TTY"));
+        }
+
+        let mut orig = unsafe { mem::zeroed::<termios>() };
+        if unsafe { tcgetattr(self.ifd, &mut orig) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Modify the original mode
+        let mut raw = orig;
+        // input modes: no break, no CR to NL, no parity check, no strip char,
+        // no start/stop output control
+        raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+        // output modes - disable post processing
+        raw.c_oflag &= !libc::OPOST;
+        // control modes - set 8 bit chars
+        raw.c_cflag |= libc::CS8;
+        // local modes - echoing off, canonical off, no extended functions,
+        // no signal chars (^Z,^C)
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+        // control chars - set return condition: min number of bytes and timer.
+        // We want read to return every single byte, without timeout
+        raw.c_cc[libc::VMIN] = 1; // 1 byte
+        raw.c_cc[libc::VTIME] = 0; // no timer
+
+        if unsafe { tcsetattr(self.ifd, libc::TCSAFLUSH, &raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Keep the mode of the first call if raw mode is enabled twice
+        self.orig_termios.get_or_insert(orig);
+        Ok(())
+    }
+
+    fn disable_raw_mode(&mut self) -> io::Result<()> {
+        if let Some(orig) = self.orig_termios.take()
+            && unsafe { tcsetattr(self.ifd, libc::TCSAFLUSH, &orig) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        Some(self.ifd)
+    }
+}
+
+impl Drop for FdTerminal {
+    fn drop(&mut self) {
+        let _ = self.disable_raw_mode();
+    }
+}
+
+/// A terminal in memory, reading scripted input and capturing the output,
+/// to drive the editor without a tty, e.g. from tests. The output also goes
+/// to a [`Screen`] 24 rows high, showing what a terminal would.
+///
+/// Clones share the terminal: give one to the editor and keep another to
+/// add input and look at the output.
+///
+/// ```
+/// use linenoise_rs::{FeedResult, HeadlessTerminal, Linenoise};
+///
+/// let terminal = HeadlessTerminal::new(80);
+/// terminal.push_input(b"hello\x1b[D\x1b[DX\r");
+///
+/// let mut ln = Linenoise::new();
+/// ln.edit_start_with(terminal.clone(), "> ").unwrap();
+/// assert_eq!(ln.edit_feed().unwrap(), FeedResult::Line("helXlo".to_string()));
+/// ln.edit_stop().unwrap();
+///
+/// assert!(terminal.output().contains("\r> helXlo"));
+/// assert_eq!(terminal.screen().row_text(0), "> helXlo");
+/// assert!(!terminal.is_raw());
+/// ```
+#[derive(Clone, Debug)]
+pub struct HeadlessTerminal {
+    state: Arc<Mutex<HeadlessState>>,
+}
+
+#[derive(Debug)]
+struct HeadlessState {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    screen: Screen,
+    raw: bool,
+}
+
+impl HeadlessTerminal {
+    /// A terminal `columns` wide, with no input.
+    pub fn new(columns: usize) -> Self {
+        HeadlessTerminal {
+            state: Arc::new(Mutex::new(HeadlessState {
+                input: VecDeque::new(),
+                output: Vec::new(),
+                screen: Screen::new(columns, 24),
+                raw: false,
+            })),
+        }
+    }
+
+    /// Add bytes to the input, read after those added before.
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.state().input.extend(bytes);
+    }
+
+    /// Resize the terminal, as seen by the next refresh.
+    pub fn set_columns(&self, columns: usize) {
+        let mut state = self.state();
+        let rows = state.screen.rows();
+        state.screen.resize(columns, rows);
+    }
+
+    /// Everything written to the terminal so far, escape sequences
+    /// included.
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.state().output).into_owned()
+    }
+
+    /// Take what was written to the terminal so far, leaving it empty.
+    pub fn take_output(&self) -> String {
+        String::from_utf8_lossy(&mem::take(&mut self.state().output)).into_owned()
+    }
+
+    /// What the output so far shows on the screen.
+    pub fn screen(&self) -> Screen {
+        self.state().screen.clone()
+    }
+
+    /// Whether the terminal is in raw mode.
+    pub fn is_raw(&self) -> bool {
+        self.state().raw
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, HeadlessState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl TerminalBackend for HeadlessTerminal {
+    /// Read scripted input, the end of it being the end of the input.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        let n = buf.len().min(state.input.len());
+        for (byte, input) in buf.iter_mut().zip(state.input.drain(..n)) {
+            *byte = input;
+        }
+        Ok(n)
+    }
+
+    /// Whether there is input left, without waiting: all of it was there
+    /// from the start.
+    fn wait_input(&mut self, _timeout: Duration) -> io::Result<bool> {
+        Ok(!self.state().input.is_empty())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        state.output.extend_from_slice(buf);
+        state.screen.feed(buf);
+        Ok(())
+    }
+
+    fn columns(&mut self) -> usize {
+        self.state().screen.columns()
+    }
+
+    fn enable_raw_mode(&mut self) -> io::Result<()> {
+        self.state().raw = true;
+        Ok(())
+    }
+
+    fn disable_raw_mode(&mut self) -> io::Result<()> {
+        self.state().raw = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,625 @@
+//! Vi editing mode. Insert mode edits like the default Emacs mode, normal
+//! mode takes vi commands: motions, operators, counts and `.` repeat.
+
+use std::cmp::{max, min};
+use std::io;
+use std::iter;
+use std::mem;
+use std::ops::Range;
+
+use super::{
+    change_case, grapheme_boundaries, next_grapheme, prev_grapheme, Context, EditKind, Editor,
+    FeedResult, LINENOISE_MAX_LINE,
+};
+
+/// The vi mode the line is edited in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum ViMode {
+    Insert,
+    Normal,
+}
+
+/// Vi state of the line being edited.
+pub(super) struct ViState {
+    pub(super) mode: ViMode,
+    /// Keys of the normal mode command typed so far.
+    pending: Vec<char>,
+    /// Last `f`, `F`, `t` or `T` and its target, for `;` and `,`.
+    last_find: Option<(char, char)>,
+    /// Last change, for `.`.
+    last_change: Option<Change>,
+    /// Cursor position when the last change entered insert mode.
+    insert_start: Option<usize>,
+    /// Times the text typed in insert mode is inserted, from the count of
+    /// `i`, `a`, `I` or `A`.
+    insert_count: usize,
+}
+
+impl ViState {
+    pub(super) fn new() -> Self {
+        ViState {
+            mode: ViMode::Insert,
+            pending: Vec::new(),
+            last_find: None,
+            last_change: None,
+            insert_start: None,
+            insert_count: 1,
+        }
+    }
+}
+
+/// A change that `.` can repeat, with the text typed in insert mode if
+/// the command entered it.
+#[derive(Clone)]
+struct Change {
+    count: Option<usize>,
+    command: Command,
+    text: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Motion {
+    Left,
+    Right,
+    /// `w`, or `W` for blank separated words.
+    WordStart(bool),
+    /// `b` or `B`.
+    WordBack(bool),
+    /// `e` or `E`.
+    WordEnd(bool),
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    /// `f`, `F`, `t` or `T` looking for `target`, possibly repeated by `;`
+    /// or `,`.
+    Find {
+        cmd: char,
+        target: char,
+        repeat: bool,
+    },
+    /// `;`, or `,` to repeat the last find in the other direction.
+    RepeatFind(bool),
+    /// The whole line, for `dd`, `cc` and `yy`.
+    Line,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Move(Motion),
+    /// `d`, `c` or `y` applied to a motion.
+    Operator(char, Motion),
+    /// Any other command, with its character argument for `r`.
+    Other(char, Option<char>),
+}
+
+impl Command {
+    /// Whether `.` repeats this command.
+    fn is_change(self) -> bool {
+        match self {
+            Command::Move(_) => false,
+            Command::Operator(op, _) => op != 'y',
+            Command::Other(c, _) => "xXsSDCr~pPiaIA".contains(c),
+        }
+    }
+}
+
+enum Parse<T> {
+    Incomplete,
+    Invalid,
+    Done(T),
+}
+
+/// Parse a count at the start of `keys`, returning it and the number of
+/// keys it takes. A leading `0` is the line start motion, not a count.
+fn parse_count(keys: &[char]) -> (Option<usize>, usize) {
+    let digits = keys
+        .iter()
+        .enumerate()
+        .take_while(|&(i, c)| c.is_ascii_digit() && (i > 0 || *c != '0'))
+        .count();
+    if digits == 0 {
+        return (None, 0);
+    }
+    let count = keys[..digits].iter().fold(0usize, |n, c| {
+        n.saturating_mul(10)
+            .saturating_add(c.to_digit(10).unwrap() as usize)
+    });
+    (Some(min(count, LINENOISE_MAX_LINE)), digits)
+}
+
+fn parse_motion(keys: &[char]) -> Parse<Motion> {
+    let Some(&c) = keys.first() else {
+        return Parse::Incomplete;
+    };
+    Parse::Done(match c {
+        'h' => Motion::Left,
+        'l' | ' ' => Motion::Right,
+        'w' | 'W' => Motion::WordStart(c == 'W'),
+        'b' | 'B' => Motion::WordBack(c == 'B'),
+        'e' | 'E' => Motion::WordEnd(c == 'E'),
+        '0' => Motion::LineStart,
+        '^' => Motion::FirstNonBlank,
+        '$' => Motion::LineEnd,
+        'f' | 'F' | 't' | 'T' => match keys.get(1) {
+            Some(&target) => Motion::Find {
+                cmd: c,
+                target,
+                repeat: false,
+            },
+            None => return Parse::Incomplete,
+        },
+        ';' => Motion::RepeatFind(false),
+        ',' => Motion::RepeatFind(true),
+        _ => return Parse::Invalid,
+    })
+}
+
+/// Parse a normal mode command: `[count] motion`, `[count] operator
+/// [count] motion`, or `[count] command`.
+fn parse_command(keys: &[char]) -> Parse<(Option<usize>, Command)> {
+    let (count, n) = parse_count(keys);
+    let keys = &keys[n..];
+    let Some(&c) = keys.first() else {
+        return Parse::Incomplete;
+    };
+
+    let command = match c {
+        'd' | 'c' | 'y' => {
+            let (count2, n) = parse_count(&keys[1..]);
+            let rest = &keys[1 + n..];
+            let motion = if rest.first() == Some(&c) {
+                Motion::Line
+            } else {
+                match parse_motion(rest) {
+                    Parse::Done(motion) => motion,
+                    Parse::Incomplete => return Parse::Incomplete,
+                    Parse::Invalid => return Parse::Invalid,
+                }
+            };
+            let count = match (count, count2) {
+                (None, None) => None,
+                (a, b) => Some(min(
+                    a.unwrap_or(1).saturating_mul(b.unwrap_or(1)),
+                    LINENOISE_MAX_LINE,
+                )),
+            };
+            return Parse::Done((count, Command::Operator(c, motion)));
+        }
+        'r' => match keys.get(1) {
+            Some(&ch) => Command::Other(c, Some(ch)),
+            None => return Parse::Incomplete,
+        },
+        'x' | 'X' | 's' | 'S' | 'D' | 'C' | '~' | 'p' | 'P' | 'u' | '.' | 'i' | 'a' | 'I' | 'A'
+        | 'j' | 'k' | '/' | '?' => Command::Other(c, None),
+        _ => match parse_motion(keys) {
+            Parse::Done(motion) => Command::Move(motion),
+            Parse::Incomplete => return Parse::Incomplete,
+            Parse::Invalid => return Parse::Invalid,
+        },
+    };
+    Parse::Done((count, command))
+}
+
+/// Character class for word motions: blank, word, or punctuation. For
+/// `big` words everything that is not blank is alike.
+fn char_class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+/// The class of each of `chars` for word motions, a grapheme cluster
+/// taking the class of its first character.
+fn char_classes(chars: &[char], big: bool) -> Vec<u8> {
+    let mut classes = Vec::with_capacity(chars.len());
+    for range in grapheme_boundaries(chars).windows(2) {
+        let class = char_class(chars[range[0]], big);
+        classes.extend(iter::repeat_n(class, range[1] - range[0]));
+    }
+    classes
+}
+
+/// Index of the last character of the run of characters of the same class
+/// starting at `pos`.
+fn run_end(classes: &[u8], mut pos: usize) -> usize {
+    while pos + 1 < classes.len() && classes[pos + 1] == classes[pos] {
+        pos += 1;
+    }
+    pos
+}
+
+fn word_start(classes: &[u8], mut pos: usize) -> usize {
+    if pos < classes.len() && classes[pos] != 0 {
+        pos = run_end(classes, pos) + 1;
+    }
+    while pos < classes.len() && classes[pos] == 0 {
+        pos += 1;
+    }
+    pos
+}
+
+fn word_back(classes: &[u8], mut pos: usize) -> usize {
+    while pos > 0 && classes[pos - 1] == 0 {
+        pos -= 1;
+    }
+    if pos > 0 {
+        let class = classes[pos - 1];
+        while pos > 0 && classes[pos - 1] == class {
+            pos -= 1;
+        }
+    }
+    pos
+}
+
+fn word_end(chars: &[char], classes: &[u8], pos: usize) -> usize {
+    let mut pos = next_grapheme(chars, pos);
+    while pos < classes.len() && classes[pos] == 0 {
+        pos += 1;
+    }
+    if pos >= classes.len() {
+        return prev_grapheme(chars, chars.len());
+    }
+    // On the last grapheme cluster of the word
+    prev_grapheme(chars, run_end(classes, pos) + 1)
+}
+
+/// Where `f`, `F`, `t` or `T` moves the cursor from `pos`, if the target
+/// is found.
+fn find(chars: &[char], pos: usize, cmd: char, target: char, repeat: bool) -> Option<usize> {
+    // A repeated t or T must not stop right before the target it is at
+    let skip = usize::from(repeat && matches!(cmd, 't' | 'T'));
+    if matches!(cmd, 'f' | 't') {
+        let from = pos + 1 + skip;
+        let found = from + chars.get(from..)?.iter().position(|&c| c == target)?;
+        Some(if cmd == 't' { found - 1 } else { found })
+    } else {
+        let found = chars[..pos.checked_sub(skip)?]
+            .iter()
+            .rposition(|&c| c == target)?;
+        Some(if cmd == 'T' { found + 1 } else { found })
+    }
+}
+
+/// Where `motion` repeated `count` times moves the cursor from `pos`.
+fn motion_target(chars: &[char], pos: usize, motion: Motion, count: usize) -> Option<usize> {
+    let len = chars.len();
+    Some(match motion {
+        Motion::Left => (0..count).fold(pos, |p, _| prev_grapheme(chars, p)),
+        Motion::Right => graphemes_right(chars, pos, count),
+        Motion::WordStart(big) => {
+            let classes = char_classes(chars, big);
+            (0..count).fold(pos, |p, _| word_start(&classes, p))
+        }
+        Motion::WordBack(big) => {
+            let classes = char_classes(chars, big);
+            (0..count).fold(pos, |p, _| word_back(&classes, p))
+        }
+        Motion::WordEnd(big) => {
+            let classes = char_classes(chars, big);
+            (0..count).fold(pos, |p, _| word_end(chars, &classes, p))
+        }
+        Motion::LineStart => 0,
+        Motion::FirstNonBlank => first_non_blank(chars),
+        Motion::LineEnd => prev_grapheme(chars, len),
+        Motion::Find {
+            cmd,
+            target,
+            repeat,
+        } => (0..count).try_fold(pos, |p, i| find(chars, p, cmd, target, repeat || i > 0))?,
+        Motion::RepeatFind(_) | Motion::Line => pos,
+    })
+}
+
+/// The position `count` grapheme clusters right of `pos`, or the end of
+/// the line.
+fn graphemes_right(chars: &[char], pos: usize, count: usize) -> usize {
+    (0..count).fold(pos, |p, _| next_grapheme(chars, p))
+}
+
+fn first_non_blank(chars: &[char]) -> usize {
+    chars
+        .iter()
+        .position(|c| !c.is_whitespace())
+        .unwrap_or(chars.len())
+}
+
+impl Editor {
+    /// Handle a character typed in vi normal mode.
+    pub(super) fn handle_vi_key(&mut self, ctx: &mut Context, ch: char) -> io::Result<FeedResult> {
+        self.vi.pending.push(ch);
+        match parse_command(&self.vi.pending) {
+            Parse::Incomplete => {}
+            Parse::Invalid => {
+                self.vi.pending.clear();
+                self.terminal.beep();
+            }
+            Parse::Done((count, command)) => {
+                self.vi.pending.clear();
+                self.vi_execute(ctx, count, command, false)?;
+                // The cursor stays on a character in normal mode
+                let len = self.buffer.chars.len();
+                if self.vi.mode == ViMode::Normal && self.buffer.pos >= len {
+                    self.buffer.pos = prev_grapheme(&self.buffer.chars, len);
+                }
+                self.refresh_line(ctx)?;
+            }
+        }
+
+        Ok(FeedResult::Pending)
+    }
+
+    /// Handle a lone Esc: leave insert mode, or cancel the pending command
+    /// in normal mode.
+    pub(super) fn vi_escape(&mut self, ctx: &mut Context) -> io::Result<()> {
+        match self.vi.mode {
+            ViMode::Insert => {
+                // Remember what was typed for `.`, and type it again for a
+                // count
+                if let Some(start) = self.vi.insert_start.take()
+                    && self.buffer.pos >= start
+                {
+                    let text: String = self.buffer.chars[start..self.buffer.pos].iter().collect();
+                    let count = mem::replace(&mut self.vi.insert_count, 1);
+                    let pos = self.buffer.pos;
+                    self.buffer.replace(pos..pos, &text.repeat(count - 1));
+                    if let Some(change) = self.vi.last_change.as_mut() {
+                        change.text = text;
+                    }
+                }
+                self.vi.mode = ViMode::Normal;
+                self.buffer.move_left();
+            }
+            ViMode::Normal => {
+                if mem::take(&mut self.vi.pending).is_empty() {
+                    self.terminal.beep();
+                }
+            }
+        }
+        self.refresh_line(ctx)
+    }
+
+    /// Switch to insert mode. Unless replaying, what gets typed is
+    /// recorded as part of the last change.
+    fn vi_insert(&mut self, replay: bool) {
+        self.vi.mode = ViMode::Insert;
+        self.vi.insert_count = 1;
+        if !replay {
+            self.vi.insert_start = Some(self.buffer.pos);
+            // Undo the change together with the text typed
+            self.edit_kind = EditKind::Insert;
+        }
+    }
+
+    /// Delete `range` of the line into the kill ring, leaving the cursor
+    /// at its start.
+    fn vi_delete(&mut self, ctx: &mut Context, range: Range<usize>) {
+        let text: String = self.buffer.chars.drain(range.clone()).collect();
+        if !text.is_empty() {
+            ctx.kill_ring.kill(&text, false, false);
+        }
+        self.buffer.pos = range.start;
+    }
+
+    /// Record a find for `;` and `,`, or turn `;` and `,` into the find
+    /// they repeat.
+    fn vi_resolve_motion(&mut self, motion: Motion) -> Option<Motion> {
+        match motion {
+            Motion::Find {
+                cmd,
+                target,
+                repeat: false,
+            } => {
+                self.vi.last_find = Some((cmd, target));
+                Some(motion)
+            }
+            Motion::RepeatFind(reverse) => {
+                let (cmd, target) = self.vi.last_find?;
+                let cmd = match cmd {
+                    _ if !reverse => cmd,
+                    'f' => 'F',
+                    'F' => 'f',
+                    't' => 'T',
+                    _ => 't',
+                };
+                Some(Motion::Find {
+                    cmd,
+                    target,
+                    repeat: true,
+                })
+            }
+            motion => Some(motion),
+        }
+    }
+
+    /// The part of the line an operator applies to.
+    fn vi_operator_range(
+        &mut self,
+        op: char,
+        motion: Motion,
+        count: usize,
+    ) -> Option<Range<usize>> {
+        let chars = &self.buffer.chars;
+        let pos = self.buffer.pos;
+        let len = chars.len();
+
+        match motion {
+            Motion::Line => return Some(0..len),
+            // Like vi, `cw` on a word changes up to its end only
+            Motion::WordStart(big)
+                if op == 'c' && chars.get(pos).is_some_and(|c| !c.is_whitespace()) =>
+            {
+                let classes = char_classes(chars, big);
+                let end =
+                    (1..count).fold(run_end(&classes, pos), |p, _| word_end(chars, &classes, p));
+                return Some(pos..next_grapheme(chars, end));
+            }
+            _ => {}
+        }
+
+        let motion = self.vi_resolve_motion(motion)?;
+        let target = motion_target(&self.buffer.chars, pos, motion, count)?;
+        let inclusive = matches!(
+            motion,
+            Motion::WordEnd(_) | Motion::LineEnd | Motion::Find { cmd: 'f' | 't', .. }
+        );
+        let end = if inclusive {
+            next_grapheme(&self.buffer.chars, max(pos, target))
+        } else {
+            max(pos, target)
+        };
+        Some(min(pos, target)..min(end, len))
+    }
+
+    /// Execute a normal mode command. When replaying it for `.`, it is not
+    /// recorded as the last change.
+    fn vi_execute(
+        &mut self,
+        ctx: &mut Context,
+        count: Option<usize>,
+        command: Command,
+        replay: bool,
+    ) -> io::Result<()> {
+        if command.is_change() && !replay {
+            self.vi.last_change = Some(Change {
+                count,
+                command,
+                text: String::new(),
+            });
+        }
+
+        let n = count.unwrap_or(1);
+        let pos = self.buffer.pos;
+        let len = self.buffer.chars.len();
+        let right = graphemes_right(&self.buffer.chars, pos, n);
+
+        match command {
+            Command::Move(motion) => {
+                match self
+                    .vi_resolve_motion(motion)
+                    .and_then(|m| motion_target(&self.buffer.chars, pos, m, n))
+                {
+                    Some(target) => self.buffer.pos = target,
+                    None => self.terminal.beep(),
+                }
+            }
+            Command::Operator(op, motion) => {
+                let Some(range) = self.vi_operator_range(op, motion, n) else {
+                    self.terminal.beep();
+                    return Ok(());
+                };
+                if op == 'y' {
+                    let text: String = self.buffer.chars[range.clone()].iter().collect();
+                    if !text.is_empty() {
+                        ctx.kill_ring.kill(&text, false, false);
+                    }
+                    self.buffer.pos = range.start;
+                } else {
+                    self.vi_delete(ctx, range);
+                    if op == 'c' {
+                        self.vi_insert(replay);
+                    }
+                }
+            }
+            Command::Other(c, arg) => match c {
+                'x' => self.vi_delete(ctx, pos..right),
+                'X' => {
+                    let left = (0..n).fold(pos, |p, _| prev_grapheme(&self.buffer.chars, p));
+                    self.vi_delete(ctx, left..pos);
+                }
+                'D' => self.vi_delete(ctx, pos..len),
+                's' | 'S' | 'C' => {
+                    let range = match c {
+                        's' => pos..right,
+                        'S' => 0..len,
+                        _ => pos..len,
+                    };
+                    self.vi_delete(ctx, range);
+                    self.vi_insert(replay);
+                }
+                'r' => {
+                    // Only if there are n characters to replace
+                    if graphemes_right(&self.buffer.chars, pos, n - 1) < len
+                        && let Some(ch) = arg
+                    {
+                        self.buffer.replace(pos..right, &ch.to_string().repeat(n));
+                        self.buffer.move_left();
+                    } else {
+                        self.terminal.beep();
+                    }
+                }
+                '~' => {
+                    for ch in &mut self.buffer.chars[pos..right] {
+                        *ch = change_case(*ch, ch.is_lowercase());
+                    }
+                    self.buffer.pos = right;
+                }
+                'p' | 'P' => {
+                    let Some(text) = ctx.kill_ring.get(0) else {
+                        self.terminal.beep();
+                        return Ok(());
+                    };
+                    let at = if c == 'p' {
+                        next_grapheme(&self.buffer.chars, pos)
+                    } else {
+                        pos
+                    };
+                    self.buffer.replace(at..at, &text.repeat(n));
+                    // Leave the cursor on the last pasted character
+                    self.buffer.move_left();
+                }
+                'u' => {
+                    for _ in 0..n {
+                        self.undo(ctx, false)?;
+                    }
+                }
+                '.' => {
+                    let Some(change) = self.vi.last_change.clone() else {
+                        self.terminal.beep();
+                        return Ok(());
+                    };
+                    self.vi_execute(ctx, count.or(change.count), change.command, true)?;
+                    if self.vi.mode == ViMode::Insert {
+                        let pos = self.buffer.pos;
+                        let count = mem::replace(&mut self.vi.insert_count, 1);
+                        self.buffer.replace(pos..pos, &change.text.repeat(count));
+                        self.vi.mode = ViMode::Normal;
+                        self.buffer.move_left();
+                    }
+                }
+                'i' | 'a' | 'I' | 'A' => {
+                    self.buffer.pos = match c {
+                        'i' => pos,
+                        'a' => next_grapheme(&self.buffer.chars, pos),
+                        'I' => first_non_blank(&self.buffer.chars),
+                        _ => len,
+                    };
+                    self.vi_insert(replay);
+                    self.vi.insert_count = n;
+                }
+                'j' | 'k' => {
+                    // Lines of a multi-line input first, then history
+                    for _ in 0..n {
+                        let moved = if c == 'k' {
+                            self.buffer.move_line_up()
+                        } else {
+                            self.buffer.move_line_down()
+                        };
+                        if moved {
+                            self.refresh_line(ctx)?;
+                        } else {
+                            self.handle_history(ctx, if c == 'k' { 1 } else { -1 })?;
+                        }
+                    }
+                }
+                '/' | '?' => self.start_search(ctx, c == '?', true)?,
+                _ => self.terminal.beep(),
+            },
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+//! Editing driven through a headless terminal, without a tty.
+
+use linenoise_rs::{
+    linenoise_history_add, linenoise_set_completion_callback, Candidate, Cmd, CompletionCallback,
+    CompletionMode, EditMode, Error, FeedResult, HeadlessTerminal, HintsCallback, KeyEvent,
+    Linenoise, LinenoiseState, PasteNewlines, Validation,
+};
+
+/// Edit a line on a fresh terminal, typing `input` in one go.
+fn edit(ln: &mut Linenoise, input: &[u8]) -> (Result<FeedResult, Error>, HeadlessTerminal) {
+    let terminal = HeadlessTerminal::new(80);
+    terminal.push_input(input);
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    let result = loop {
+        match ln.edit_feed() {
+            Ok(FeedResult::Pending) => continue,
+            result => break result,
+        }
+    };
+    ln.edit_stop().unwrap();
+    (result, terminal)
+}
+
+fn read_line(ln: &mut Linenoise, input: &[u8]) -> String {
+    match edit(ln, input).0 {
+        Ok(FeedResult::Line(line)) => line,
+        other => panic!("no line for {input:?}: {other:?}"),
+    }
+}
+
+/// Like `read_line`, with Enter read on its own, so that an Esc right
+/// before it is a key too rather than Alt-Enter.
+fn read_line_then_enter(ln: &mut Linenoise, input: &[u8]) -> String {
+    ln.edit_start_with(HeadlessTerminal::new(80), "> ").unwrap();
+    assert_eq!(ln.feed_bytes(input).unwrap(), FeedResult::Pending);
+    let result = ln.feed_bytes(b"\r").unwrap();
+    ln.edit_stop().unwrap();
+    match result {
+        FeedResult::Line(line) => line,
+        other => panic!("no line for {input:?}: {other:?}"),
+    }
+}
+
+#[test]
+fn typing_and_cursor_movement() {
+    let mut ln = Linenoise::new();
+    assert_eq!(read_line(&mut ln, b"world\x01hello \r"), "hello world");
+    assert_eq!(read_line(&mut ln, b"abc\x1b[D\x1b[DX\x05Y\r"), "aXbcY");
+    assert_eq!(read_line(&mut ln, b"abc\x7f\x7fd\r"), "ad");
+}
+
+#[test]
+fn word_commands() {
+    let mut ln = Linenoise::new();
+    assert_eq!(read_line(&mut ln, b"one two three\x17\r"), "one two ");
+    assert_eq!(read_line(&mut ln, b"one two\x1bb\x1bu\r"), "one TWO");
+    assert_eq!(read_line(&mut ln, b"one two\x1bt\r"), "two one");
+}
+
+#[test]
+fn word_commands_keep_grapheme_clusters() {
+    let mut ln = Linenoise::new();
+    ln.set_word_chars(|c| c.is_alphanumeric() || c == '_');
+    let line = "cafe\u{301} bar";
+    let mut input = |keys: &str| read_line(&mut ln, format!("{line}\x01{keys}\r").as_bytes());
+    assert_eq!(input("\x1bfZ"), "cafe\u{301}Z bar");
+    assert_eq!(input("\x1bd"), " bar");
+    assert_eq!(input("\x1bu"), "CAFE\u{301} bar");
+}
+
+#[test]
+fn kill_and_yank() {
+    let mut ln = Linenoise::new();
+    assert_eq!(
+        read_line(&mut ln, b"hello world\x01\x0b\x19\x19\r"),
+        "hello worldhello world"
+    );
+    assert_eq!(read_line(&mut ln, b"abc\x15xyz\x19\r"), "xyzabc");
+    // Kills in a row go to the same entry
+    assert_eq!(
+        read_line(&mut ln, b"one two three\x17\x17\x19\r"),
+        "one two three"
+    );
+    assert_eq!(read_line(&mut ln, b"ab cd\x01\x1bd\x1bd\x19\r"), "ab cd");
+    assert_eq!(
+        read_line(&mut ln, b"one two three\x17\x01\x0b\x19\r"),
+        "one two "
+    );
+    // Alt-Y replaces the yanked text with older kills, in a cycle
+    let mut ln = Linenoise::new();
+    assert_eq!(read_line(&mut ln, b"one\x17two\x17\x19\x1by\r"), "one");
+    assert_eq!(
+        read_line(&mut ln, b"one\x17two\x17\x19\x1by\x1by\x1by\r"),
+        "one"
+    );
+    // But only right after a yank
+    assert_eq!(read_line(&mut ln, b"one\x17x\x1by\r"), "x");
+}
+
+#[test]
+fn key_bindings() {
+    let mut ln = Linenoise::new();
+    ln.bind_key(&[KeyEvent::ctrl('x'), 'h'.into()], Cmd::MoveHome);
+    assert_eq!(read_line(&mut ln, b"world\x18hhello \r"), "hello world");
+
+    // Closures edit the line and the cursor, counted in characters
+    ln.bind_key_fn(&[KeyEvent::ctrl('g')], |line, pos| {
+        *line = format!("«{line}»");
+        *pos = line.chars().count() - 1;
+    });
+    assert_eq!(read_line(&mut ln, b"ab\x07c\r"), "«abc»");
+
+    // Unbound control keys do nothing
+    ln.unbind_key(&[KeyEvent::ctrl('a')]);
+    assert_eq!(read_line(&mut ln, b"bc\x01a\r"), "bca");
+}
+
+#[test]
+fn undo_and_redo() {
+    let mut ln = Linenoise::new();
+    assert_eq!(read_line(&mut ln, b"abc def\x17\x1f\r"), "abc def");
+    assert_eq!(read_line(&mut ln, b"abc\x15\x1f\x18\x12\r"), "");
+    // Typing is undone a run at a time, deleting a character at a time
+    assert_eq!(read_line(&mut ln, b"abc def\x1f\r"), "");
+    assert_eq!(read_line(&mut ln, b"abc\x7f\x7f\x1f\r"), "ab");
+    assert_eq!(read_line(&mut ln, b"abc\x01\x0b\x1f\x1f\x18\x12\r"), "abc");
+    // A new edit drops what could be redone
+    assert_eq!(read_line(&mut ln, b"abc\x1fx\x18\x12\r"), "x");
+}
+
+#[test]
+fn history_navigation() {
+    let mut ln = Linenoise::new();
+    ln.history_add("first");
+    ln.history_add("second");
+    assert_eq!(read_line(&mut ln, b"\x1b[A\r"), "second");
+    assert_eq!(read_line(&mut ln, b"\x1b[A\x1b[A\r"), "first");
+    assert_eq!(read_line(&mut ln, b"\x1b[A\x1b[A\x1b[B\r"), "second");
+    assert_eq!(read_line(&mut ln, b"\x12fir\r"), "first");
+}
+
+#[test]
+fn prefix_history_navigation() {
+    let mut ln = Linenoise::new();
+    for entry in ["git status", "ls", "git log", "cargo"] {
+        ln.history_add(entry);
+    }
+    ln.set_history_prefix_search(true);
+    assert_eq!(read_line(&mut ln, b"git\x1b[A\r"), "git log");
+    assert_eq!(read_line(&mut ln, b"git\x1b[A\x1b[A\r"), "git status");
+    assert_eq!(read_line(&mut ln, b"git\x1b[A\x1b[A\x1b[A\r"), "git status");
+    assert_eq!(read_line(&mut ln, b"git\x1b[A\x1b[B\r"), "git");
+    // The cursor stays after the typed prefix
+    assert_eq!(read_line(&mut ln, b"git\x1b[AX\r"), "gitX log");
+    // Without a prefix every entry matches
+    assert_eq!(read_line(&mut ln, b"\x1b[A\x1b[A\r"), "git log");
+}
+
+#[test]
+fn incremental_search() {
+    let mut ln = Linenoise::new();
+    for entry in ["git status", "ls", "git log", "cargo"] {
+        ln.history_add(entry);
+    }
+    assert_eq!(read_line(&mut ln, b"\x12git\r"), "git log");
+    assert_eq!(read_line(&mut ln, b"\x12git\x12\r"), "git status");
+    assert_eq!(read_line(&mut ln, b"\x12git\x12\x13\r"), "git log");
+    assert_eq!(read_line(&mut ln, b"\x12gitz\x7f\r"), "git log");
+    // Ctrl-G gives up the search
+    assert_eq!(read_line(&mut ln, b"x\x12git\x07\r"), "x");
+    // History navigation goes on from the match
+    assert_eq!(read_line(&mut ln, b"\x12l\x1b[A\r"), "ls");
+    assert_eq!(read_line(&mut ln, b"\x12git\x12\x1b[B\r"), "ls");
+}
+
+#[test]
+fn completion() {
+    let mut ln = Linenoise::new();
+    ln.set_completion_callback(|line, completions| {
+        for word in ["hello", "help"] {
+            if word.starts_with(line) {
+                completions.push(word.to_string());
+            }
+        }
+    });
+    assert_eq!(read_line(&mut ln, b"hel\t\r"), "hello");
+    assert_eq!(read_line(&mut ln, b"hel\t\t\r"), "help");
+
+    // Plain functions of the old callback types still fit
+    let completion: CompletionCallback = |line, completions| completions.push(format!("{line}!"));
+    let hints: HintsCallback = |line| (line == "hi!").then(|| (" there".to_string(), 35, false));
+    ln.set_completion_callback(completion);
+    ln.set_hints_callback(hints);
+    let (result, terminal) = edit(&mut ln, b"hi\t\r");
+    assert_eq!(result.unwrap(), FeedResult::Line("hi!".to_string()));
+    assert!(terminal.output().contains(" there"));
+}
+
+/// Complete the word before the cursor, ignoring case.
+fn complete_words(ln: &mut Linenoise) {
+    ln.set_completer(|line, pos, candidates| {
+        // `pos` counts characters, so find its byte offset first
+        let end = line.char_indices().nth(pos).map_or(line.len(), |(i, _)| i);
+        let start = line[..end].rfind(' ').map_or(0, |i| i + 1);
+        let word = line[start..end].to_lowercase();
+        let start = line[..start].chars().count();
+        for candidate in ["hello", "help", "Helium", "world", "worm"] {
+            if candidate.to_lowercase().starts_with(&word) {
+                candidates.push(Candidate::new(start..pos, candidate));
+            }
+        }
+    });
+}
+
+#[test]
+fn completing_the_word_at_the_cursor() {
+    let mut ln = Linenoise::new();
+    complete_words(&mut ln);
+    assert_eq!(read_line(&mut ln, b"say hel\t\r"), "say hello");
+    assert_eq!(read_line(&mut ln, b"say hel\t\t\t\r"), "say Helium");
+    assert_eq!(
+        read_line(&mut ln, b"say hel lo\x1b[D\x1b[D\x1b[D\t\r"),
+        "say hello lo"
+    );
+    assert_eq!(read_line(&mut ln, "café hel\t\r".as_bytes()), "café hello");
+    assert_eq!(
+        read_line(&mut ln, "déjà wo lü\x1b[D\x1b[D\x1b[D\t\r".as_bytes()),
+        "déjà world lü"
+    );
+}
+
+#[test]
+fn completion_list() {
+    let mut ln = Linenoise::new();
+    complete_words(&mut ln);
+    ln.set_completion_mode(CompletionMode::List);
+    let (_, terminal) = edit(&mut ln, b"say hel\t");
+    assert!(terminal.output().contains("hello   help    Helium"));
+    assert_eq!(read_line(&mut ln, b"say hel\tp\r"), "say help");
+    assert_eq!(read_line(&mut ln, b"say wor\tl\t\r"), "say world");
+
+    // Asking first about long lists
+    ln.set_completion_query_items(2);
+    let (_, terminal) = edit(&mut ln, b"say hel\tn");
+    assert!(terminal
+        .output()
+        .contains("Display all 3 possibilities? (y or n)"));
+    assert!(!terminal.output().contains("Helium"));
+    let (_, terminal) = edit(&mut ln, b"say hel\ty");
+    assert!(terminal.output().contains("Helium"));
+}
+
+#[test]
+fn completion_menu() {
+    let mut ln = Linenoise::new();
+    complete_words(&mut ln);
+    ln.set_completion_mode(CompletionMode::Menu);
+    // Enter takes the selection, then the line
+    assert_eq!(read_line(&mut ln, b"say hel\t\r\r"), "say hello");
+    assert_eq!(read_line(&mut ln, b"say hel\t\t\r\r"), "say help");
+    assert_eq!(
+        read_line(&mut ln, b"say hel\t\x1b[C\x1b[C\r\r"),
+        "say Helium"
+    );
+    assert_eq!(read_line(&mut ln, b"say hel\t\t\x1b[Z\r\r"), "say hello");
+    // Other keys take the selection and edit as usual
+    assert_eq!(read_line(&mut ln, b"say hel\t\tx\r"), "say helpx");
+
+    // Esc restores the line
+    ln.edit_start_with(HeadlessTerminal::new(80), "> ").unwrap();
+    assert_eq!(ln.feed_bytes(b"say hel\t\t").unwrap(), FeedResult::Pending);
+    assert_eq!(ln.feed_bytes(b"\x1b").unwrap(), FeedResult::Pending);
+    assert_eq!(
+        ln.feed_bytes(b"\r").unwrap(),
+        FeedResult::Line("say hel".to_string())
+    );
+    ln.edit_stop().unwrap();
+}
+
+#[test]
+fn completion_common_prefix() {
+    let mut ln = Linenoise::new();
+    complete_words(&mut ln);
+    ln.set_completion_prefix(true);
+    assert_eq!(read_line(&mut ln, b"say w\t\r"), "say wor");
+    assert_eq!(read_line(&mut ln, b"say w\t\t\r"), "say world");
+    assert_eq!(read_line(&mut ln, b"say wo\t\t\t\r"), "say worm");
+    ln.set_completion_ignore_case(true);
+    assert_eq!(read_line(&mut ln, b"say he\t\r"), "say hel");
+}
+
+#[test]
+fn vi_mode() {
+    let mut ln = Linenoise::new();
+    ln.set_edit_mode(EditMode::Vi);
+    let terminal = HeadlessTerminal::new(80);
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    // An Esc at the end of the input is a key of its own
+    for keys in [&b"one two three\x1b"[..], b"bdw", b"0cwsix\x1b", b"$p"] {
+        terminal.push_input(keys);
+        assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    }
+    terminal.push_input(b"\r");
+    assert_eq!(
+        ln.edit_feed().unwrap(),
+        FeedResult::Line("six two one".to_string())
+    );
+    ln.edit_stop().unwrap();
+}
+
+#[test]
+fn vi_operators() {
+    let mut ln = Linenoise::new();
+    ln.set_edit_mode(EditMode::Vi);
+    let line = "one two three";
+    let mut input =
+        |keys: &str| read_line_then_enter(&mut ln, format!("{line}\x1b{keys}").as_bytes());
+    assert_eq!(input("0dw"), "two three");
+    assert_eq!(input("0d2w"), "three");
+    assert_eq!(input("02dw"), "three");
+    assert_eq!(input("bd$"), "one two ");
+    assert_eq!(input("0dtt"), "two three");
+    assert_eq!(input("0dft"), "wo three");
+    assert_eq!(input("$dF "), "one twoe");
+    assert_eq!(input("0wcwX\x1b"), "one X three");
+    assert_eq!(input("0cc1\x1b"), "1");
+    assert_eq!(input("dd"), "");
+    assert_eq!(input("0x3x"), "two three");
+    assert_eq!(input("$2X"), "one two the");
+    assert_eq!(input("0ft;D"), "one two ");
+    // Copy and paste
+    assert_eq!(input("0yeP"), "oneone two three");
+    assert_eq!(input("0ywP"), "one one two three");
+    assert_eq!(input("0dwwP"), "two one three");
+    // Repeat and undo
+    assert_eq!(input("0dw."), "three");
+    assert_eq!(input("0dwu"), "one two three");
+}
+
+#[test]
+fn vi_words_keep_grapheme_clusters() {
+    let mut ln = Linenoise::new();
+    ln.set_edit_mode(EditMode::Vi);
+    let line = "cafe\u{301} bar";
+    let mut input = |keys: &str| read_line(&mut ln, format!("{line}\x1b0{keys}\r").as_bytes());
+    assert_eq!(input("wiX"), "cafe\u{301} Xbar");
+    assert_eq!(input("eaX"), "cafe\u{301}X bar");
+    assert_eq!(input("dw"), "bar");
+    assert_eq!(input("de"), " bar");
+    assert_eq!(input("cwX"), "X bar");
+}
+
+#[test]
+fn vi_insert_counts() {
+    let mut ln = Linenoise::new();
+    ln.set_edit_mode(EditMode::Vi);
+    assert_eq!(read_line_then_enter(&mut ln, b"ab\x1b3ix\x1b"), "axxxb");
+    assert_eq!(read_line(&mut ln, b"ab\x1b2a-\x1b0.\r"), "a--b--");
+}
+
+#[test]
+fn vi_search() {
+    let mut ln = Linenoise::new();
+    ln.set_edit_mode(EditMode::Vi);
+    ln.history_add("first");
+    ln.history_add("second");
+    // Enter ends the search in normal mode
+    assert_eq!(read_line(&mut ln, b"\x1b/fir\rA!\r"), "first!");
+    assert_eq!(read_line(&mut ln, b"\x1b/sec\x1bx\r"), "");
+}
+
+#[test]
+fn esc_read_with_the_next_key() {
+    let mut ln = Linenoise::new();
+    // Alt+Z isn't bound, so it's Esc and Z
+    assert_eq!(read_line(&mut ln, b"ab\x1bZ\r"), "abZ");
+    // Esc cancels a search before the key after it
+    ln.history_add("first");
+    assert_eq!(read_line(&mut ln, b"\x12fir\x1bZ\r"), "Z");
+
+    ln.set_edit_mode(EditMode::Vi);
+    assert_eq!(read_line(&mut ln, b"abc\x1b0ix\r"), "xabc");
+    ln.history_add("older");
+    assert_eq!(read_line(&mut ln, b"abc\x1bk\r"), "older");
+}
+
+#[test]
+fn end_of_editing() {
+    let mut ln = Linenoise::new();
+    assert!(matches!(
+        edit(&mut ln, b"abc\x03").0,
+        Ok(FeedResult::Interrupted)
+    ));
+    assert!(matches!(edit(&mut ln, b"\x04").0, Ok(FeedResult::Eof)));
+    assert!(matches!(edit(&mut ln, b"abc").0, Err(Error::Closed)));
+}
+
+#[test]
+fn raw_mode_and_bracketed_paste() {
+    let mut ln = Linenoise::new();
+    let terminal = HeadlessTerminal::new(80);
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    assert!(terminal.is_raw());
+    assert!(terminal.output().starts_with("\x1b[?2004h"));
+    ln.edit_stop().unwrap();
+    assert!(!terminal.is_raw());
+    assert!(terminal.output().ends_with("\x1b[?2004l"));
+}
+
+#[test]
+fn paste() {
+    let mut ln = Linenoise::new();
+    assert_eq!(read_line(&mut ln, b"a\x1b[200~b\tc\x1b[201~d\r"), "ab\tcd");
+    ln.set_paste_newlines(PasteNewlines::Strip);
+    assert_eq!(
+        read_line(&mut ln, b"\x1b[200~one\ntwo\x1b[201~\r"),
+        "onetwo"
+    );
+    // Line breaks made by the filter are dealt with like the others
+    ln.set_paste_filter(|text| text.replace('\t', "\n").to_uppercase());
+    assert_eq!(read_line(&mut ln, b"a\x1b[200~b\tc\x1b[201~d\r"), "aBCd");
+}
+
+#[test]
+fn paste_submitting_lines() {
+    let mut ln = Linenoise::new();
+    ln.set_paste_newlines(PasteNewlines::Submit);
+    assert_eq!(
+        read_line(&mut ln, b"\x1b[200~one\ntwo\nthr\x1b[201~"),
+        "one"
+    );
+    assert_eq!(read_line(&mut ln, b""), "two");
+    assert_eq!(read_line(&mut ln, b"ee\r"), "three");
+
+    // The validator decides on each line as on Enter
+    ln.set_validator(|input| {
+        if input.ends_with('\\') {
+            Validation::Incomplete
+        } else if input.contains("bad") {
+            Validation::Invalid("no bad words".to_string())
+        } else {
+            Validation::Complete
+        }
+    });
+    assert_eq!(
+        read_line(&mut ln, b"\x1b[200~one\\\ntwo\n\x1b[201~"),
+        "one\\\ntwo"
+    );
+    let (result, terminal) = edit(&mut ln, b"\x1b[200~bad\n\x1b[201~");
+    assert!(matches!(result, Err(Error::Closed)));
+    assert!(terminal.output().contains("no bad words"));
+}
+
+#[test]
+fn multi_line_input() {
+    let mut ln = Linenoise::new();
+    ln.set_validator(|input| {
+        if input.ends_with('\\') {
+            Validation::Incomplete
+        } else if input.contains("bad") {
+            Validation::Invalid("no bad words".to_string())
+        } else {
+            Validation::Complete
+        }
+    });
+    assert_eq!(read_line(&mut ln, b"one\\\rtwo\r"), "one\\\ntwo");
+    assert_eq!(read_line(&mut ln, b"one\x1b\rtwo\x1b[AX\r"), "oneX\ntwo");
+    // Moving between lines steps over whole grapheme clusters
+    assert_eq!(
+        read_line(&mut ln, "ae\u{301}b\x1b\rxy\x1b[AX\r".as_bytes()),
+        "ae\u{301}Xb\nxy"
+    );
+    assert_eq!(
+        read_line(&mut ln, "xy\x1b\rae\u{301}b\x1b[A\x1b[BX\r".as_bytes()),
+        "xy\nae\u{301}Xb"
+    );
+
+    // The error shows until the next key
+    let terminal = HeadlessTerminal::new(80);
+    terminal.push_input(b"bad\r");
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    assert!(terminal.take_output().contains("no bad words"));
+    terminal.push_input(b"\x7f\x7f\x7fgood\r");
+    assert_eq!(
+        ln.edit_feed().unwrap(),
+        FeedResult::Line("good".to_string())
+    );
+    assert!(!terminal.output().contains("no bad words"));
+    ln.edit_stop().unwrap();
+}
+
+#[test]
+fn vi_multi_line_input() {
+    let mut ln = Linenoise::new();
+    ln.set_edit_mode(EditMode::Vi);
+    ln.set_validator(|input| {
+        if input.ends_with('\\') {
+            Validation::Incomplete
+        } else {
+            Validation::Complete
+        }
+    });
+    assert_eq!(read_line(&mut ln, b"one\\\rtwo\r"), "one\\\ntwo");
+    // Alt-Enter inserts a line break in insert mode too
+    assert_eq!(read_line(&mut ln, b"one\x1b\rtwo\r"), "one\ntwo");
+    assert_eq!(read_line(&mut ln, b"one\x1b\rtwo\x1bkx\r"), "on\ntwo");
+}
+
+#[test]
+fn input_after_the_line_is_kept() {
+    let mut ln = Linenoise::new();
+    let terminal = HeadlessTerminal::new(80);
+    terminal.push_input(b"one\rtwo\r");
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Line("one".to_string()));
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Line("two".to_string()));
+    ln.edit_stop().unwrap();
+}
+
+#[test]
+fn feed_bytes() {
+    let mut ln = Linenoise::new();
+    ln.edit_start_with(HeadlessTerminal::new(80), "> ").unwrap();
+    assert_eq!(ln.feed_bytes(b"ab").unwrap(), FeedResult::Pending);
+    assert_eq!(ln.feed_bytes(b"c\x1b").unwrap(), FeedResult::Pending);
+    assert_eq!(
+        ln.feed_bytes(b"\r").unwrap(),
+        FeedResult::Line("abc".to_string())
+    );
+    ln.edit_stop().unwrap();
+}
+
+#[test]
+fn single_redraw_per_batch() {
+    let mut ln = Linenoise::new();
+    let terminal = HeadlessTerminal::new(80);
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    terminal.take_output();
+    terminal.push_input(b"hello");
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    assert_eq!(terminal.take_output(), "\r> hello\x1b[0K\r\x1b[7C");
+    ln.edit_stop().unwrap();
+}
+
+#[test]
+fn scrolling_a_long_line() {
+    let mut ln = Linenoise::new();
+    let terminal = HeadlessTerminal::new(10);
+    terminal.push_input(b"abcdefghijkl\x1b[D\x1b[D\x1b[D\x1b[D");
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    terminal.take_output();
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    assert_eq!(terminal.take_output(), "\r> fghijkl\x1b[0K\r\x1b[5C");
+    ln.edit_stop().unwrap();
+}
+
+#[test]
+fn free_functions_from_callbacks() {
+    linenoise_set_completion_callback(|line, completions| {
+        linenoise_history_add(line);
+        completions.push(format!("{line}!"));
+    });
+    let terminal = HeadlessTerminal::new(80);
+    terminal.push_input(b"hi\t\r");
+    let mut state = LinenoiseState::edit_start_with(terminal.clone(), "> ").unwrap();
+    assert_eq!(
+        state.edit_feed().unwrap(),
+        FeedResult::Line("hi!".to_string())
+    );
+    state.edit_stop().unwrap();
+
+    terminal.push_input(b"\x1b[A\r");
+    let mut state = LinenoiseState::edit_start_with(terminal, "> ").unwrap();
+    assert_eq!(
+        state.edit_feed().unwrap(),
+        FeedResult::Line("hi".to_string())
+    );
+}
+
+#[test]
+fn default_instance_states_are_independent() {
+    let one = HeadlessTerminal::new(80);
+    let two = HeadlessTerminal::new(80);
+    let mut first = LinenoiseState::edit_start_with(one.clone(), "1> ").unwrap();
+    let mut second = LinenoiseState::edit_start_with(two.clone(), "2> ").unwrap();
+
+    one.push_input(b"abc");
+    two.push_input(b"xyz");
+    assert_eq!(first.edit_feed().unwrap(), FeedResult::Pending);
+    assert_eq!(second.edit_feed().unwrap(), FeedResult::Pending);
+    // Input after the line stays with its terminal
+    one.push_input(b"\rSECRET");
+    assert_eq!(
+        first.edit_feed().unwrap(),
+        FeedResult::Line("abc".to_string())
+    );
+
+    drop(first);
+    assert!(!one.is_raw());
+    assert!(two.is_raw());
+    two.push_input(b"\r");
+    assert_eq!(
+        second.edit_feed().unwrap(),
+        FeedResult::Line("xyz".to_string())
+    );
+}
@@ -0,0 +1,69 @@
+//! Saving and loading history files.
+
+use std::env;
+use std::fs;
+
+use linenoise_rs::{FeedResult, HeadlessTerminal, Linenoise};
+
+/// A history file path for the test `name`.
+fn history_path(name: &str) -> String {
+    let path = env::temp_dir().join(format!("linenoise-{name}-{}", std::process::id()));
+    path.to_str().unwrap().to_string()
+}
+
+/// The history entry `back` entries back from the newest, through Up.
+fn recall(ln: &mut Linenoise, back: usize) -> String {
+    ln.edit_start_with(HeadlessTerminal::new(80), "> ").unwrap();
+    let result = ln.feed_bytes(&b"\x1b[A".repeat(back)).unwrap();
+    assert_eq!(result, FeedResult::Pending);
+    let result = ln.feed_bytes(b"\r").unwrap();
+    ln.edit_stop().unwrap();
+    match result {
+        FeedResult::Line(line) => line,
+        other => panic!("no line: {other:?}"),
+    }
+}
+
+#[test]
+fn save_and_load_round_trip() {
+    let path = history_path("round-trip");
+    let entries = ["dir C:\\", "ls", "f(a,\nb)", "a\\nb", "\\\\"];
+
+    let mut ln = Linenoise::new();
+    for entry in entries {
+        ln.history_add(entry);
+    }
+    ln.history_save(&path).unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        saved,
+        "#linenoise-history escaped\ndir C:\\\\\nls\nf(a,\\nb)\na\\\\nb\n\\\\\\\\\n"
+    );
+
+    let mut loaded = Linenoise::new();
+    loaded.history_load(&path).unwrap();
+    assert_eq!(recall(&mut loaded, 1), "\\\\");
+    loaded.history_save(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), saved);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn plain_files_load_as_they_are() {
+    let path = history_path("plain");
+    fs::write(&path, "printf \"a\\n\"\ndir C:\\\n\\\\server\\share\n").unwrap();
+
+    let mut ln = Linenoise::new();
+    ln.history_load(&path).unwrap();
+    assert_eq!(recall(&mut ln, 1), "\\\\server\\share");
+    assert_eq!(recall(&mut ln, 2), "dir C:\\");
+    assert_eq!(recall(&mut ln, 3), "printf \"a\\n\"");
+
+    // And are saved the same way without line breaks or backslashes
+    let mut ln = Linenoise::new();
+    ln.history_add("ls");
+    ln.history_add("cd /tmp");
+    ln.history_save(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "ls\ncd /tmp\n");
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,283 @@
+//! What the editor shows on screen, through the virtual screen of a
+//! headless terminal.
+
+use linenoise_rs::{
+    Color, FeedResult, HeadlessTerminal, Hint, Linenoise, Screen, Span, Style, Validation,
+};
+
+/// Start editing on a terminal `columns` wide and type `input`, returning
+/// the terminal to look at its screen.
+fn type_keys(ln: &mut Linenoise, columns: usize, input: &[u8]) -> HeadlessTerminal {
+    let terminal = HeadlessTerminal::new(columns);
+    ln.edit_start_with(terminal.clone(), "> ").unwrap();
+    terminal.push_input(input);
+    if !input.is_empty() {
+        assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    }
+    terminal
+}
+
+/// The rows of the screen up to the last one that isn't blank.
+fn shown(screen: &Screen) -> Vec<String> {
+    let mut lines = screen.lines();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+#[test]
+fn screen_wraps_and_scrolls() {
+    let mut screen = Screen::new(4, 2);
+    screen.feed(b"abcd");
+    assert_eq!(screen.cursor(), (0, 3));
+    screen.feed(b"e");
+    assert_eq!(screen.lines(), ["abcd", "e"]);
+    screen.feed(b"fgh\r\nij");
+    assert_eq!(screen.lines(), ["efgh", "ij"]);
+    assert_eq!(screen.cursor(), (1, 2));
+}
+
+#[test]
+fn screen_moves_and_erases() {
+    let mut screen = Screen::new(10, 3);
+    screen.feed(b"one\r\ntwo\r\nthree");
+    screen.feed(b"\x1b[2A\r\x1b[2C\x1b[0K");
+    assert_eq!(screen.lines(), ["on", "two", "three"]);
+    screen.feed(b"\x1b[B\r\x1b[0J");
+    assert_eq!(screen.lines(), ["on", "", ""]);
+    screen.feed(b"\x1b[3;4Hx\x1b[H\x1b[2J");
+    assert_eq!(screen.lines(), ["", "", ""]);
+    assert_eq!(screen.cursor(), (0, 0));
+}
+
+#[test]
+fn screen_tracks_styles() {
+    let mut screen = Screen::new(20, 1);
+    screen.feed(b"a\x1b[1;31mb\x1b[0mc\x1b[38;5;244;48;2;1;2;3md\x1b[7me\x1b]0;title\x07");
+    assert_eq!(screen.row_text(0), "abcde");
+    let style = |col| screen.cell(0, col).unwrap().style;
+    assert_eq!(style(0), Style::new());
+    assert_eq!(style(1), Style::new().fg(Color::Red).bold());
+    assert_eq!(style(2), Style::new());
+    assert_eq!(
+        style(3),
+        Style::new().fg(Color::Ansi256(244)).bg(Color::Rgb(1, 2, 3))
+    );
+    assert!(!screen.cell(0, 3).unwrap().reverse);
+    assert!(screen.cell(0, 4).unwrap().reverse);
+}
+
+#[test]
+fn screen_measures_wide_characters() {
+    let mut screen = Screen::new(5, 2);
+    screen.feed("日本語".as_bytes());
+    assert_eq!(screen.lines(), ["日本", "語"]);
+    assert_eq!(screen.cursor(), (1, 2));
+    screen.feed("\re\u{301}".as_bytes());
+    assert_eq!(screen.row_text(1), "e\u{301}");
+    assert_eq!(screen.cursor(), (1, 1));
+}
+
+#[test]
+fn cursor_after_accented_characters() {
+    for columns in [10, 20, 80] {
+        let mut ln = Linenoise::new();
+        let terminal = type_keys(&mut ln, columns, "héllo\x1b[D\x1b[D".as_bytes());
+        let screen = terminal.screen();
+        assert_eq!(shown(&screen), ["> héllo"], "{columns} columns");
+        assert_eq!(screen.cursor(), (0, 5), "{columns} columns");
+    }
+}
+
+#[test]
+fn single_line_scrolls_horizontally() {
+    let mut ln = Linenoise::new();
+    let terminal = type_keys(&mut ln, 10, b"abcdefghijklmnop\x01");
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> abcdefg"]);
+    assert_eq!(screen.cursor(), (0, 2));
+}
+
+#[test]
+fn multi_line_mode_wraps_rows() {
+    for (columns, rows) in [
+        (10, &["> abcdefgh", "ijklmnopqr", "stuvwxyz"][..]),
+        (15, &["> abcdefghijklm", "nopqrstuvwxyz"]),
+        (28, &["> abcdefghijklmnopqrstuvwxyz"]),
+    ] {
+        let mut ln = Linenoise::new();
+        ln.set_multi_line(true);
+        let terminal = type_keys(&mut ln, columns, b"abcdefghijklmnopqrstuvwxyz");
+        let screen = terminal.screen();
+        assert_eq!(shown(&screen), rows, "{columns} columns");
+        let last = rows.len() - 1;
+        let expected = if rows[last].len() == columns {
+            // The cursor goes on a row of its own past a full one
+            (last + 1, 0)
+        } else {
+            (last, rows[last].len())
+        };
+        assert_eq!(screen.cursor(), expected, "{columns} columns");
+
+        terminal.push_input(b"\x01\x1b[C");
+        assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+        let screen = terminal.screen();
+        assert_eq!(shown(&screen), rows, "{columns} columns");
+        assert_eq!(screen.cursor(), (0, 3), "{columns} columns");
+    }
+}
+
+#[test]
+fn multi_line_mode_clears_rows_left_over() {
+    let mut ln = Linenoise::new();
+    ln.set_multi_line(true);
+    let terminal = type_keys(&mut ln, 10, b"abcdefghijklmnopqrstuvwxyz");
+    terminal.push_input(b"\x15x");
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> x"]);
+    assert_eq!(screen.cursor(), (0, 3));
+}
+
+#[test]
+fn wide_characters_wrap_whole() {
+    let mut ln = Linenoise::new();
+    ln.set_multi_line(true);
+    let terminal = type_keys(&mut ln, 7, "日本語テキスト".as_bytes());
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> 日本", "語テキ", "スト"]);
+    assert_eq!(screen.cursor(), (2, 4));
+}
+
+#[test]
+fn continuation_prompts() {
+    let mut ln = Linenoise::new();
+    ln.set_validator(|input| {
+        if input.matches('(').count() > input.matches(')').count() {
+            Validation::Incomplete
+        } else {
+            Validation::Complete
+        }
+    });
+    let terminal = type_keys(&mut ln, 20, b"f(a,\rb,\rc");
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> f(a,", "... b,", "... c"]);
+    assert_eq!(screen.cursor(), (2, 5));
+
+    terminal.push_input(b"\x1b[A\x1b[A");
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    assert_eq!(terminal.screen().cursor(), (0, 3));
+
+    terminal.push_input(b")\r");
+    assert_eq!(
+        ln.edit_feed().unwrap(),
+        FeedResult::Line("f)(a,\nb,\nc".to_string())
+    );
+    assert_eq!(terminal.screen().cursor(), (3, 0));
+}
+
+#[test]
+fn highlighting() {
+    let mut ln = Linenoise::new();
+    ln.set_highlighter(|line, _pos| {
+        line.chars()
+            .enumerate()
+            .filter(|(_, c)| c.is_ascii_digit())
+            .map(|(i, _)| Span::new(i..i + 1, Style::new().fg(Color::Red)))
+            .collect()
+    });
+    let terminal = type_keys(&mut ln, 20, "é1b".as_bytes());
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> é1b"]);
+    assert_eq!(screen.cell(0, 2).unwrap().style, Style::new());
+    assert_eq!(
+        screen.cell(0, 3).unwrap().style,
+        Style::new().fg(Color::Red)
+    );
+    assert_eq!(screen.cell(0, 4).unwrap().style, Style::new());
+}
+
+#[test]
+fn hints_below_the_line() {
+    let mut ln = Linenoise::new();
+    ln.set_hinter(|line| {
+        line.starts_with("git").then(|| {
+            Hint::new("usage: git <command>")
+                .style(Style::new().dim())
+                .below()
+        })
+    });
+    let terminal = type_keys(&mut ln, 12, b"git");
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> git", "usage: git <"]);
+    assert_eq!(screen.cursor(), (0, 5));
+    assert!(screen.cell(1, 0).unwrap().style.dim);
+
+    terminal.push_input(b"\r");
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Line("git".to_string()));
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> git"]);
+    assert_eq!(screen.cursor(), (1, 0));
+}
+
+#[test]
+fn resizing() {
+    let mut ln = Linenoise::new();
+    let terminal = type_keys(&mut ln, 20, b"abcdefghijkl");
+    terminal.set_columns(10);
+    terminal.push_input(b"m");
+    assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> ghijklm"]);
+    assert_eq!(screen.cursor(), (0, 9));
+}
+
+#[test]
+fn suggestions_are_dim_until_accepted() {
+    let mut ln = Linenoise::new();
+    ln.set_autosuggest(true);
+    ln.history_add("git status");
+    let terminal = type_keys(&mut ln, 20, b"git s");
+    let screen = terminal.screen();
+    assert_eq!(shown(&screen), ["> git status"]);
+    assert_eq!(screen.cursor(), (0, 7));
+    assert!(!screen.cell(0, 6).unwrap().style.dim);
+    for column in 7..12 {
+        assert!(screen.cell(0, column).unwrap().style.dim);
+    }
+    ln.edit_stop().unwrap();
+
+    // Right, End and Ctrl-E accept all of it
+    for key in [&b"\x1b[C"[..], b"\x1b[F", b"\x05"] {
+        let terminal = type_keys(&mut ln, 20, b"git s");
+        terminal.push_input(key);
+        assert_eq!(ln.edit_feed().unwrap(), FeedResult::Pending);
+        let screen = terminal.screen();
+        assert_eq!(shown(&screen), ["> git status"]);
+        assert_eq!(screen.cursor(), (0, 12));
+        assert!(!screen.cell(0, 11).unwrap().style.dim);
+        terminal.push_input(b"\r");
+        assert_eq!(
+            ln.edit_feed().unwrap(),
+            FeedResult::Line("git status".to_string())
+        );
+        ln.edit_stop().unwrap();
+    }
+}
+
+#[test]
+fn multi_line_suggestions_and_hints_show_their_first_line() {
+    let mut ln = Linenoise::new();
+    ln.set_autosuggest(true);
+    ln.history_add("foo(a,\nb)");
+    ln.set_hinter(|line| (line == "foo(a,").then(|| Hint::new(" x\ny")));
+    let terminal = type_keys(&mut ln, 20, b"fo");
+    assert_eq!(shown(&terminal.screen()), ["> foo(a, x"]);
+
+    terminal.push_input(b"\x05\r");
+    assert_eq!(
+        ln.edit_feed().unwrap(),
+        FeedResult::Line("foo(a,".to_string())
+    );
+}